@@ -0,0 +1,75 @@
+use clap::{Parser, Subcommand};
+use img2epub::{get_metadata, set_metadata, MetadataUpdate};
+
+#[derive(Parser, Debug)]
+#[command(version)]
+struct Args {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print the metadata of an EPUB
+    Get {
+        /// Epub file path
+        epub: String,
+    },
+
+    /// Rewrite the metadata of an EPUB in place
+    /// Only content.opf is changed; all other entries are kept as they are
+    Set {
+        /// Epub file path
+        epub: String,
+
+        /// New title of the book
+        #[clap(short, long)]
+        title: Option<String>,
+
+        /// New author of the book
+        #[clap(short, long)]
+        creator: Option<String>,
+
+        /// New publisher of the book
+        #[clap(short, long)]
+        publisher: Option<String>,
+
+        /// New date of the book
+        /// The format is ISO 8601 (e.g. 2021-07-04T12:34:56Z)
+        #[clap(long)]
+        date: Option<String>,
+    },
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+
+    match args.command {
+        Command::Get { epub } => {
+            let metadata = get_metadata(&epub)?;
+            println!("title: {}", metadata.title);
+            println!("creator: {}", metadata.creator.unwrap_or_default());
+            println!("publisher: {}", metadata.publisher.unwrap_or_default());
+            println!("date: {}", metadata.date.unwrap_or_default());
+        }
+        Command::Set {
+            epub,
+            title,
+            creator,
+            publisher,
+            date,
+        } => {
+            set_metadata(
+                &epub,
+                &MetadataUpdate {
+                    title,
+                    creator,
+                    publisher,
+                    date,
+                },
+            )?;
+        }
+    }
+
+    Ok(())
+}
@@ -1,19 +1,46 @@
-use clap::Parser;
+use std::{
+    fs::{create_dir_all, remove_dir_all},
+    path::Path,
+    str::FromStr,
+    sync::mpsc::channel,
+    time::Duration,
+};
 
-use img2epub::{img2epub, EpubOptions};
+use clap::{Parser, Subcommand};
+
+use img2epub::{
+    detect_panels, find_book_dirs, img2epub, insert_pages, merge_volumes, prune_cache,
+    split_volumes, write_panel_overlay, Color, ConversionReport, EpubOptions, EpubVersion,
+    InsertOptions, MergeOptions, Orientation, Padding, PageNumbering, PageOptions, ResizeFilter,
+    SortStrategy, SplitMode, Spread, Unsharp, Vendor,
+};
+use notify::{recommended_watcher, Event, EventKind, RecursiveMode, Watcher};
+use uuid::Uuid;
 
 #[derive(Parser, Debug)]
-#[command(version)]
+#[command(
+    version,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
+#[expect(
+    clippy::struct_excessive_bools,
+    reason = "each flag is a command line switch"
+)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Directory of the images
-    directory: String,
+    #[clap(required = true)]
+    directory: Option<String>,
 
     /// Output file name
     output: Option<String>,
 
     /// Title of the book
     /// If not specified, the title is read from metadata.json
-    #[clap(short, long)]
+    #[clap(short, long, conflicts_with = "batch")]
     title: Option<String>,
 
     /// Author of the book
@@ -44,18 +71,307 @@ struct Args {
     /// If set, add a blank page to the beginning of the book
     #[clap(short, long)]
     blank: bool,
+
+    /// Split the book into volumes of at most this many pages
+    /// The output is treated as a directory for the volumes
+    #[clap(long, group = "split")]
+    split_pages: Option<usize>,
+
+    /// Split the book into volumes of at most this size of source images
+    /// (e.g. 50M, 2G), which only approximates the size of the volumes
+    /// The output is treated as a directory for the volumes
+    #[clap(long, group = "split", value_parser = parse_size)]
+    split_source_size: Option<u64>,
+
+    /// Split the book into one volume per subdirectory
+    /// The output is treated as a directory for the volumes
+    #[clap(long, group = "split")]
+    split_chapters: bool,
+
+    /// Convert every book folder in the directory
+    /// A book folder is a subdirectory containing images or a metadata.json,
+    /// and is titled after its name if it has no metadata.json.
+    /// The output is treated as a directory for the books
+    #[clap(long)]
+    batch: bool,
+
+    /// Keep running and rebuild the book whenever the images or
+    /// metadata.json change
+    /// Only changed pages are processed again
+    #[clap(long, conflicts_with = "batch")]
+    watch: bool,
+
+    /// Directory to cache processed pages in
+    /// Pages whose image and options have not changed since an earlier run
+    /// are reused instead of being processed again
+    #[clap(long)]
+    cache_dir: Option<String>,
+
+    /// How the pages are ordered
+    /// "natural" sorts the paths with numbers compared by value,
+    /// "last-number" by the last number in the file name,
+    /// "lexical" by the paths as they are, and "mtime" by modification time
+    #[clap(long, default_value = "natural", value_parser = SortStrategy::from_str)]
+    sort: SortStrategy,
+
+    /// Leave out images that cannot be decoded instead of failing
+    /// The skipped files are listed after the conversion
+    #[clap(long)]
+    skip_bad: bool,
+
+    /// Color transparent areas of the images are composited over
+    /// The value is "white", "black", "#rgb" or "#rrggbb"
+    #[clap(long, default_value = "white", value_parser = Color::from_str)]
+    background: Color,
+
+    /// Color of the padding around pages smaller than the largest one
+    /// The value is a color as for --background, or "auto" to use the
+    /// average color of the edge of each page
+    #[clap(long, default_value = "white", value_parser = Padding::from_str)]
+    padding: Padding,
+
+    /// Keep every page at its own size instead of padding all pages to the
+    /// size of the largest one
+    /// Landscape pages are then shown on their own instead of in a spread
+    #[clap(long)]
+    native_size: bool,
+
+    /// Shrink pages whose longest side is larger than this many pixels
+    #[clap(long)]
+    max_dimension: Option<u32>,
+
+    /// Shrink pages larger than this many megapixels (e.g. 4.5)
+    #[clap(long)]
+    max_megapixels: Option<f64>,
+
+    /// Resampling filter used to shrink pages
+    /// The value is "nearest", "triangle", "catmull-rom", "gaussian" or
+    /// "lanczos3"
+    #[clap(long, default_value = "lanczos3", value_parser = ResizeFilter::from_str)]
+    filter: ResizeFilter,
+
+    /// Sharpen pages after shrinking them, given as SIGMA or
+    /// SIGMA,THRESHOLD (e.g. 0.8,2)
+    #[clap(long, value_parser = Unsharp::from_str)]
+    unsharp: Option<Unsharp>,
+
+    /// Encode the pages as JPEG at lower qualities, then shrink them, until
+    /// each EPUB is no larger than this size
+    /// (e.g. 50M, 200M)
+    /// The settings used are printed after the conversion
+    #[clap(long, value_parser = parse_size)]
+    max_size: Option<u64>,
+
+    /// Image used as the cover instead of the first page
+    /// It may be one of the pages or a separate image
+    /// If not specified, the cover is read from metadata.json
+    #[clap(long, conflicts_with = "batch")]
+    cover: Option<String>,
+
+    /// Do not show the cover as a page of its own, e.g. when it is the same
+    /// image as the first page
+    #[clap(long)]
+    no_cover_page: bool,
+
+    /// Draw a cover with the title, series and creator instead of using an
+    /// image
+    #[clap(long, conflicts_with = "cover")]
+    generate_cover: bool,
+
+    /// Add a title page listing the metadata after the cover
+    #[clap(long)]
+    title_page: bool,
+
+    /// Number the pages in the page list, counting from the first page after
+    /// the cover
+    /// Labels given in pages.txt take precedence, and a page labelled with a
+    /// number continues the count from there
+    #[clap(long)]
+    page_numbers: bool,
+
+    /// Number of front matter pages after the cover, which are numbered in
+    /// roman numerals
+    #[clap(long, default_value_t = 0, requires = "page_numbers")]
+    front_matter: usize,
+
+    /// Which reading systems the EPUB is written for: "3", "compat" for EPUB 3
+    /// that older EPUB 2 readers can open too, or "2" for strict EPUB 2.0.1
+    #[clap(long, default_value = "3", value_parser = EpubVersion::from_str)]
+    epub_version: EpubVersion,
+
+    /// Which reading app the EPUB is tuned for: "kindle", "apple" for the
+    /// display options of Apple Books, or "kobo"
+    #[clap(long, default_value = "kindle", value_parser = Vendor::from_str)]
+    vendor: Vendor,
+
+    /// Orientation the book is read in: "auto", "portrait" or "landscape".
+    /// If not specified, the orientation is read from metadata.json
+    #[clap(long, value_parser = Orientation::from_str)]
+    orientation: Option<Orientation>,
+
+    /// When two pages are shown as a spread: "none", "landscape", "both" or
+    /// "auto". If not specified, the spread is read from metadata.json
+    #[clap(long, value_parser = Spread::from_str)]
+    spread: Option<Spread>,
+
+    /// Find the panels of pages not listed in panels.json by the gutters
+    /// between them, so that readers can zoom in panel by panel
+    #[clap(long)]
+    detect_panels: bool,
+}
+
+/// Parses a byte count with an optional K, M or G suffix.
+fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim().to_ascii_uppercase();
+    let value = value.strip_suffix('B').unwrap_or(&value);
+    let (number, unit) = match value.char_indices().last() {
+        Some((i, 'K')) => (&value[..i], 1 << 10),
+        Some((i, 'M')) => (&value[..i], 1 << 20),
+        Some((i, 'G')) => (&value[..i], 1 << 30),
+        _ => (value, 1),
+    };
+    number
+        .trim()
+        .parse::<u64>()
+        .map_err(|e| format!("invalid size: {e}"))?
+        .checked_mul(unit)
+        .ok_or_else(|| format!("size too large: {value}"))
+}
+
+/// Converts a number of megapixels to pixels.
+#[expect(
+    clippy::cast_possible_truncation,
+    clippy::cast_sign_loss,
+    reason = "the value is checked to be positive and finite"
+)]
+fn megapixels(value: f64) -> Result<u64, String> {
+    if value.is_finite() && value > 0.0 {
+        Ok((value * 1_000_000.0) as u64)
+    } else {
+        Err(format!("invalid number of megapixels: {value}"))
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Insert pages into an EPUB generated by img2epub
+    Insert {
+        /// Epub file path
+        epub: String,
+
+        /// Image files or directories of images to insert
+        #[clap(required = true)]
+        images: Vec<String>,
+
+        /// Position in the reading order to insert at (0 is the cover)
+        #[clap(long)]
+        at: usize,
+
+        /// Output file name
+        /// If not specified, the input EPUB is overwritten
+        #[clap(short, long)]
+        output: Option<String>,
+    },
+
+    /// Merge several volumes into one EPUB with a chapter per volume
+    Merge {
+        /// Image directories or EPUBs generated by img2epub, in order
+        #[clap(required = true)]
+        inputs: Vec<String>,
+
+        /// Output file name
+        #[clap(short, long)]
+        output: String,
+
+        /// Title of the book
+        /// If not specified, the title of the first volume is used
+        #[clap(short, long)]
+        title: Option<String>,
+
+        /// Author of the book
+        /// If not specified, the author of the first volume is used
+        #[clap(short, long)]
+        creator: Option<String>,
+
+        /// Publisher of the book
+        /// If not specified, the publisher of the first volume is used
+        #[clap(short, long)]
+        publisher: Option<String>,
+
+        /// Date of the book
+        /// The format is ISO 8601 (e.g. 2021-07-04T12:34:56Z)
+        #[clap(long)]
+        date: Option<String>,
+
+        /// Direction of the book ("rtl" or "ltr")
+        /// If not specified, the direction of the first volume is used
+        #[clap(short, long)]
+        direction: Option<String>,
+
+        /// Volume whose cover is used as the cover of the book, counted from 1
+        #[clap(long, default_value_t = 1)]
+        cover: usize,
+    },
+
+    /// Find the panels of images by their gutters and print them in reading
+    /// order, as x, y, width and height in percent of the image
+    Panels {
+        /// Image files
+        #[clap(required = true)]
+        images: Vec<String>,
+
+        /// Direction of the book ("rtl" or "ltr"), which orders the panels
+        /// of a row
+        #[clap(short, long)]
+        direction: Option<String>,
+
+        /// Directory to write a copy of each image to, with its panels
+        /// outlined and numbered
+        #[clap(long)]
+        overlay: Option<String>,
+    },
+
+    /// Remove pages from a cache directory
+    /// Without limits, the whole cache is cleared
+    PruneCache {
+        /// Cache directory
+        cache_dir: String,
+
+        /// Remove pages that have not been used for this many days
+        #[clap(long)]
+        max_age_days: Option<u64>,
+
+        /// Remove the least recently used pages until the cache fits this size
+        /// (e.g. 500M, 2G)
+        #[clap(long, value_parser = parse_size)]
+        max_size: Option<u64>,
+    },
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
 
-    let out = match args.output {
-        Some(x) => x,
-        None => format!("{}.epub", args.directory),
+    if let Some(command) = args.command {
+        return run(command);
+    }
+
+    let split = if let Some(n) = args.split_pages {
+        Some(SplitMode::Pages(n))
+    } else if let Some(n) = args.split_source_size {
+        Some(SplitMode::SourceSize(n))
+    } else {
+        args.split_chapters.then_some(SplitMode::Chapters)
     };
 
-    img2epub(EpubOptions {
-        image_dir: args.directory,
+    let directory = args.directory.unwrap_or_default();
+    let out = match (args.output, split.is_some() || args.batch) {
+        (Some(x), _) => x,
+        (None, true) => ".".to_string(),
+        (None, false) => format!("{directory}.epub"),
+    };
+
+    let opts = EpubOptions {
+        image_dir: directory,
         out,
         title: args.title,
         creator: args.creator,
@@ -63,7 +379,412 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         publication_date: args.date,
         is_rtl: args.direction.map(|x| x == "rtl"),
         blank: args.blank.then_some(true),
-    })?;
+        cache_dir: args.cache_dir,
+        sort: args.sort,
+        skip_bad: args.skip_bad,
+        page_options: PageOptions {
+            background: args.background,
+            padding: args.padding,
+            native_size: args.native_size,
+            max_dimension: args.max_dimension,
+            max_pixels: args.max_megapixels.map(megapixels).transpose()?,
+            filter: args.filter,
+            unsharp: args.unsharp,
+            quality: None,
+        },
+        max_size: args.max_size,
+        cover: args.cover,
+        cover_page: !args.no_cover_page,
+        generate_cover: args.generate_cover,
+        title_page: args.title_page,
+        page_numbering: args.page_numbers.then_some(PageNumbering {
+            front_matter: args.front_matter,
+        }),
+        version: args.epub_version,
+        vendor: args.vendor,
+        orientation: args.orientation,
+        spread: args.spread,
+        detect_panels: args.detect_panels,
+    };
+    if args.batch {
+        return convert_library(&opts, split.as_ref());
+    }
+    if args.watch {
+        return watch(opts, split.as_ref());
+    }
+    convert(opts, split.as_ref())?;
+
+    Ok(())
+}
+
+/// Runs a subcommand.
+fn run(command: Command) -> Result<(), Box<dyn std::error::Error>> {
+    match command {
+        Command::Insert {
+            epub,
+            images,
+            at,
+            output,
+        } => {
+            insert_pages(InsertOptions {
+                epub,
+                images,
+                position: at,
+                out: output,
+            })?;
+        }
+        Command::Merge {
+            inputs,
+            output,
+            title,
+            creator,
+            publisher,
+            date,
+            direction,
+            cover,
+        } => {
+            merge_volumes(MergeOptions {
+                inputs,
+                out: output,
+                title,
+                creator,
+                publisher,
+                publication_date: date,
+                is_rtl: direction.map(|x| x == "rtl"),
+                cover: cover.checked_sub(1).ok_or("--cover is counted from 1")?,
+            })?;
+        }
+        Command::Panels {
+            images,
+            direction,
+            overlay,
+        } => {
+            if let Some(dir) = &overlay {
+                create_dir_all(dir)?;
+            }
+            let is_rtl = direction.as_deref() == Some("rtl");
+            for image in images {
+                let path = Path::new(&image);
+                let panels = detect_panels(path, is_rtl)?;
+                println!("{image}: {} panels", panels.len());
+                for (i, panel) in panels.iter().enumerate() {
+                    println!(
+                        "  {}: {:.1}, {:.1}, {:.1}, {:.1}",
+                        i + 1,
+                        panel.x * 100.0,
+                        panel.y * 100.0,
+                        panel.width * 100.0,
+                        panel.height * 100.0
+                    );
+                }
+                if let Some(dir) = &overlay {
+                    let stem = path.file_stem().ok_or("invalid image path")?;
+                    let out = Path::new(dir).join(format!("{}.panels.png", stem.to_string_lossy()));
+                    write_panel_overlay(path, &panels, &out)?;
+                }
+            }
+        }
+        Command::PruneCache {
+            cache_dir,
+            max_age_days,
+            max_size,
+        } => {
+            let summary = prune_cache(
+                &cache_dir,
+                max_age_days.map(|x| Duration::from_secs(x * 24 * 60 * 60)),
+                max_size,
+            )?;
+            println!(
+                "Removed {} pages ({} bytes)",
+                summary.removed, summary.freed
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn convert(opts: EpubOptions, split: Option<&SplitMode>) -> anyhow::Result<ConversionReport> {
+    let has_budget = opts.max_size.is_some();
+    let report = match split {
+        Some(mode) => split_volumes(opts, mode)?,
+        None => img2epub(opts)?,
+    };
+    print_report(&report, has_budget);
+    Ok(report)
+}
+
+/// Lists the files that did not become pages, and the settings each book
+/// was fitted to its size budget with.
+fn print_report(report: &ConversionReport, has_budget: bool) {
+    for path in &report.files.ignored {
+        eprintln!("Ignored {}", path.display());
+    }
+    for (path, reason) in &report.files.failed {
+        eprintln!("Skipped {}: {reason}", path.display());
+    }
+    if has_budget {
+        for book in &report.books {
+            let limit = book
+                .page_options
+                .max_pixels
+                .map_or_else(|| "no limit".to_string(), |x| format!("at most {x} pixels"));
+            let quality = book
+                .page_options
+                .quality
+                .map_or_else(String::new, |x| format!(", JPEG quality {x}"));
+            println!("{}: {} bytes, pages {limit}{quality}", book.path, book.size);
+        }
+    }
+}
+
+/// Page cache of a watch session without `--cache-dir`, removed when the
+/// session ends.
+struct WatchCache(String);
+
+impl WatchCache {
+    fn new() -> Result<Self, Box<dyn std::error::Error>> {
+        let cache = Self(format!("/tmp/img2epub-watch-{}", Uuid::new_v4()));
+        // Stopping the session with Ctrl-C does not unwind, so the cache is
+        // removed by the handler instead of on drop
+        let dir = cache.0.clone();
+        ctrlc::set_handler(move || {
+            let _ = remove_dir_all(&dir);
+            std::process::exit(130);
+        })?;
+        Ok(cache)
+    }
+}
+
+impl Drop for WatchCache {
+    fn drop(&mut self) {
+        let _ = remove_dir_all(&self.0);
+    }
+}
+
+/// Whether `path` is written by building the book into `out`: the book
+/// itself, or with `split` a volume in the `out` directory, or a temporary
+/// file zip writes next to them (named `zi` and six random characters).
+fn is_build_output(path: &Path, out: &Path, split: bool) -> bool {
+    let out_dir = if split { Some(out) } else { out.parent() };
+    if path.parent() != out_dir {
+        return false;
+    }
+    let is_volume = split && path.extension().is_some_and(|x| x == "epub");
+    let is_zip_temp = path
+        .file_name()
+        .and_then(|x| x.to_str())
+        .is_some_and(|x| x.len() == 8 && x.starts_with("zi") && !x.contains('.'));
+    path == out || is_volume || is_zip_temp
+}
+
+/// Builds the book, then rebuilds it after every burst of changes to the
+/// image directory until the process is stopped.
+fn watch(
+    mut opts: EpubOptions,
+    split: Option<&SplitMode>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Wait this long after the last change before rebuilding, so that
+    // copying many files at once triggers a single build
+    const DEBOUNCE: Duration = Duration::from_millis(500);
+
+    let cache = if opts.cache_dir.is_none() {
+        Some(WatchCache::new()?)
+    } else {
+        None
+    };
+    if let Some(cache) = &cache {
+        opts.cache_dir = Some(cache.0.clone());
+    }
+    let out = std::path::absolute(&opts.out)?;
+
+    let (tx, rx) = channel();
+    let mut watcher = recommended_watcher(tx)?;
+    // Events name paths the way the watched directory is given, so it is
+    // made absolute to compare them with `out`
+    watcher.watch(
+        &std::path::absolute(&opts.image_dir)?,
+        RecursiveMode::Recursive,
+    )?;
+
+    loop {
+        match convert(opts.clone(), split) {
+            Ok(_) => println!("Built {}", opts.out),
+            Err(e) => eprintln!("Build failed: {e:#}"),
+        }
+        println!("Watching {} for changes...", opts.image_dir);
+
+        // Ignore events that do not change anything, such as reading the
+        // images or writing the output into the watched directory
+        let is_change = |event: &Event| {
+            !matches!(event.kind, EventKind::Access(_))
+                && event
+                    .paths
+                    .iter()
+                    .any(|x| !is_build_output(x, &out, split.is_some()))
+        };
+        loop {
+            if is_change(&rx.recv()??) {
+                break;
+            }
+        }
+        while let Ok(event) = rx.recv_timeout(DEBOUNCE) {
+            event?;
+        }
+    }
+}
+
+/// Converts each book folder of `opts.image_dir` into `opts.out`,
+/// continuing past failures, and prints a summary of the results.
+fn convert_library(
+    opts: &EpubOptions,
+    split: Option<&SplitMode>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    create_dir_all(&opts.out)?;
+
+    let mut results = Vec::new();
+    for dir in find_book_dirs(&opts.image_dir)? {
+        let name = Path::new(&dir)
+            .file_name()
+            .map_or_else(|| dir.clone(), |x| x.to_string_lossy().into_owned());
+        let out = if split.is_some() {
+            opts.out.clone()
+        } else {
+            Path::new(&opts.out)
+                .join(format!("{name}.epub"))
+                .to_string_lossy()
+                .into_owned()
+        };
+        let has_metadata = Path::new(&dir).join("metadata.json").exists();
+        let result = convert(
+            EpubOptions {
+                image_dir: dir.clone(),
+                out: out.clone(),
+                title: (!has_metadata).then(|| name.clone()),
+                creator: opts.creator.clone(),
+                publisher: opts.publisher.clone(),
+                publication_date: opts.publication_date.clone(),
+                is_rtl: opts.is_rtl,
+                blank: opts.blank,
+                cache_dir: opts.cache_dir.clone(),
+                sort: opts.sort,
+                skip_bad: opts.skip_bad,
+                page_options: opts.page_options.clone(),
+                max_size: opts.max_size,
+                cover: None,
+                cover_page: opts.cover_page,
+                generate_cover: opts.generate_cover,
+                title_page: opts.title_page,
+                page_numbering: opts.page_numbering,
+                version: opts.version,
+                vendor: opts.vendor,
+                orientation: opts.orientation,
+                spread: opts.spread,
+                detect_panels: opts.detect_panels,
+            },
+            split,
+        );
+        results.push((name, out, result));
+    }
+
+    let name_width = results
+        .iter()
+        .map(|(name, _, _)| name.chars().count())
+        .chain(std::iter::once("Book".len()))
+        .max()
+        .unwrap_or_default();
+    println!();
+    println!("{:name_width$}  {:7}  Detail", "Book", "Status");
+    for (name, out, result) in &results {
+        match result {
+            Ok(report) if report.files.failed.is_empty() => {
+                println!("{name:name_width$}  {:7}  {out}", "ok");
+            }
+            Ok(report) => println!(
+                "{name:name_width$}  {:7}  {out} ({} skipped)",
+                "ok",
+                report.files.failed.len()
+            ),
+            Err(e) => println!("{name:name_width$}  {:7}  {e:#}", "failed"),
+        }
+    }
+
+    let failed = results.iter().filter(|(_, _, x)| x.is_err()).count();
+    println!();
+    println!("{} succeeded, {failed} failed", results.len() - failed);
+    if failed > 0 {
+        return Err(format!("{failed} of {} books failed", results.len()).into());
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes_are_read_with_their_unit() {
+        assert_eq!(parse_size("300"), Ok(300));
+        assert_eq!(parse_size("2k"), Ok(2 << 10));
+        assert_eq!(parse_size("5 MB"), Ok(5 << 20));
+        assert_eq!(parse_size("1G"), Ok(1 << 30));
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("-1K").is_err());
+    }
+
+    #[test]
+    fn sizes_too_large_are_an_error() {
+        assert_eq!(parse_size("18446744073709551615"), Ok(u64::MAX));
+        assert!(parse_size("18446744073709551615K").is_err());
+        assert!(parse_size("99999999999G").is_err());
+    }
+
+    #[test]
+    fn only_the_book_and_zip_files_are_build_output() {
+        let out = Path::new("/books/images/book.epub");
+        assert!(is_build_output(out, out, false));
+        assert!(is_build_output(
+            Path::new("/books/images/zi3Xa9Qp"),
+            out,
+            false
+        ));
+        assert!(!is_build_output(
+            Path::new("/books/images/p3.png"),
+            out,
+            false
+        ));
+        assert!(!is_build_output(
+            Path::new("/books/images/zip.png"),
+            out,
+            false
+        ));
+        assert!(!is_build_output(
+            Path::new("/books/images/a/zi3Xa9Qp"),
+            out,
+            false
+        ));
+    }
+
+    #[test]
+    fn pages_under_the_volume_directory_are_not_build_output() {
+        // The default output directory of a split, with the images under it
+        let out = Path::new("/books");
+        assert!(is_build_output(
+            Path::new("/books/Title Vol. 1.epub"),
+            out,
+            true
+        ));
+        assert!(is_build_output(Path::new("/books/zi3Xa9Qp"), out, true));
+        assert!(!is_build_output(
+            Path::new("/books/images/p3.png"),
+            out,
+            true
+        ));
+        assert!(!is_build_output(
+            Path::new("/books/images/old.epub"),
+            out,
+            true
+        ));
+    }
+}
@@ -1,2 +1,15 @@
+pub mod accessibility;
+pub mod cache;
+pub mod compat;
 pub mod converter;
+pub mod decode;
+pub mod detection;
+pub mod editor;
+pub mod generated;
 pub mod images;
+pub mod pages;
+pub mod panels;
+pub mod rendition;
+#[cfg(test)]
+pub mod testing;
+pub mod vendor;
@@ -0,0 +1,301 @@
+use std::{collections::HashMap, fs::File, io::BufReader, path::Path};
+
+use anyhow::Result;
+use serde::Deserialize;
+use serde_json::from_reader;
+
+use super::{compat::EpubVersion, converter::escape_xml, generated::GeneratedPage, images::Image};
+
+/// Gives the text descriptions of pages, relative to the image directory.
+pub const ALT_TEXT_FILE: &str = "alt-text.json";
+
+/// The schema.org accessibility metadata of a book. Anything left empty is
+/// filled in from the pages when the book is written.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct Accessibility {
+    /// `accessMode`: the senses the content is meant for, such as `visual`
+    pub access_modes: Vec<String>,
+    /// `accessibilityFeature`, such as `alternativeText`
+    pub features: Vec<String>,
+    /// `accessibilityHazard`, such as `flashing`, or `none`
+    pub hazards: Vec<String>,
+    /// `accessibilitySummary`: how accessible the book is, in prose
+    pub summary: Option<String>,
+}
+
+impl Accessibility {
+    /// The metadata with what is missing filled in from `pages`, the first
+    /// of which is the cover:
+    /// - The images are visual, and text pages textual.
+    /// - The book has a table of contents, and has alternative text and
+    ///   printed page numbers if its pages have them.
+    /// - Static images have no hazards.
+    /// - The summary says whether every page is described in text.
+    fn completed(&self, pages: &[Image]) -> Self {
+        let images = pages.iter().skip(1).filter(|x| x.has_image());
+        let has_text = pages.iter().any(|x| {
+            matches!(
+                x.generated,
+                Some(GeneratedPage::TitlePage | GeneratedPage::Text)
+            )
+        });
+
+        let or = |given: &[String], default: Vec<&str>| {
+            if given.is_empty() {
+                default.into_iter().map(String::from).collect()
+            } else {
+                given.to_vec()
+            }
+        };
+        let mut modes = vec!["visual"];
+        if has_text {
+            modes.push("textual");
+        }
+        let mut features = vec!["tableOfContents"];
+        if images
+            .clone()
+            .any(|x| x.alt.as_ref().is_some_and(|x| !x.is_empty()))
+        {
+            features.push("alternativeText");
+        }
+        if pages.iter().any(|x| x.label.is_some()) {
+            features.extend(["pageNavigation", "printPageNumbers"]);
+        }
+        Self {
+            access_modes: or(&self.access_modes, modes),
+            features: or(&self.features, features),
+            hazards: or(&self.hazards, vec!["none"]),
+            summary: Some(self.summary.clone().unwrap_or_else(|| {
+                if all_described(pages) {
+                    "Comic in fixed layout. Every page is an image with a text description."
+                } else {
+                    "Comic in fixed layout. The pages are images, and not every page has a text description."
+                }
+                .to_string()
+            })),
+        }
+    }
+
+    /// The metadata read back from a book with `pages`, without what
+    /// [`Self::meta_tags`] filled in, so that it is filled in afresh when the
+    /// pages change.
+    #[must_use]
+    pub fn without_defaults(self, pages: &[Image]) -> Self {
+        let defaults = Self::default().completed(pages);
+        let given = |x: Vec<String>, default: Vec<String>| {
+            if x == default {
+                Vec::new()
+            } else {
+                x
+            }
+        };
+        Self {
+            access_modes: given(self.access_modes, defaults.access_modes),
+            features: given(self.features, defaults.features),
+            hazards: given(self.hazards, defaults.hazards),
+            summary: self
+                .summary
+                .filter(|x| Some(x) != defaults.summary.as_ref()),
+        }
+    }
+
+    /// The metadata, completed from `pages`, as `schema:` properties of the
+    /// package. Besides the access modes, the pages are sufficient when seen,
+    /// and also when read if every page has a description.
+    #[must_use]
+    pub fn meta_tags(&self, pages: &[Image], version: EpubVersion) -> String {
+        let completed = self.completed(pages);
+        let mut properties = completed
+            .access_modes
+            .iter()
+            .map(|x| ("accessMode", x.as_str()))
+            .collect::<Vec<_>>();
+        properties.push(("accessModeSufficient", "visual"));
+        if all_described(pages) {
+            properties.push(("accessModeSufficient", "textual"));
+        }
+        properties.extend(
+            completed
+                .features
+                .iter()
+                .map(|x| ("accessibilityFeature", x.as_str())),
+        );
+        properties.extend(
+            completed
+                .hazards
+                .iter()
+                .map(|x| ("accessibilityHazard", x.as_str())),
+        );
+        if let Some(summary) = &completed.summary {
+            properties.push(("accessibilitySummary", summary));
+        }
+        properties
+            .into_iter()
+            .map(|(name, value)| {
+                if version.is_epub3() {
+                    format!(
+                        r#"
+        <meta property="schema:{name}">{}</meta>"#,
+                        escape_xml(value)
+                    )
+                } else {
+                    format!(
+                        r#"
+        <meta name="schema:{name}" content="{}"/>"#,
+                        escape_xml(value)
+                    )
+                }
+            })
+            .collect::<Vec<_>>()
+            .concat()
+    }
+}
+
+/// Whether every image page after the cover has a text description.
+fn all_described(pages: &[Image]) -> bool {
+    pages
+        .iter()
+        .skip(1)
+        .filter(|x| x.has_image())
+        .all(|x| x.alt.is_some())
+}
+
+/// Reads the text descriptions listed in `alt-text.json` in the image
+/// directory, by file path relative to the directory.
+///
+/// # Errors
+///
+/// Returns an error if the file exists but cannot be read or parsed.
+pub fn read_alt_text_json(image_dir: &str) -> Result<HashMap<String, String>> {
+    let path = Path::new(image_dir).join(ALT_TEXT_FILE);
+    if !path.exists() {
+        return Ok(HashMap::new());
+    }
+    Ok(from_reader(BufReader::new(File::open(path)?))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::epub::converter::Metadata;
+
+    fn page(alt: Option<&str>, label: Option<&str>) -> Image {
+        Image {
+            alt: alt.map(String::from),
+            label: label.map(String::from),
+            ..Image::default()
+        }
+    }
+
+    /// Values of the `schema:{name}` properties in `tags`.
+    fn values<'a>(tags: &'a str, name: &str) -> Vec<&'a str> {
+        let open = format!(r#"<meta property="schema:{name}">"#);
+        tags.split(open.as_str())
+            .skip(1)
+            .filter_map(|x| x.split("</meta>").next())
+            .collect()
+    }
+
+    #[test]
+    fn features_follow_the_pages() {
+        let pages = [page(None, None), page(None, None), page(None, None)];
+        let tags = Accessibility::default().meta_tags(&pages, EpubVersion::default());
+        assert_eq!(values(&tags, "accessibilityFeature"), ["tableOfContents"]);
+        assert_eq!(values(&tags, "accessMode"), ["visual"]);
+        assert_eq!(values(&tags, "accessibilityHazard"), ["none"]);
+
+        let pages = [
+            page(None, None),
+            page(Some("A"), Some("1")),
+            page(None, None),
+        ];
+        let tags = Accessibility::default().meta_tags(&pages, EpubVersion::default());
+        assert_eq!(
+            values(&tags, "accessibilityFeature"),
+            [
+                "tableOfContents",
+                "alternativeText",
+                "pageNavigation",
+                "printPageNumbers"
+            ]
+        );
+    }
+
+    #[test]
+    fn summary_says_whether_every_page_is_described() {
+        // The cover needs no description of its own
+        let pages = [
+            page(None, None),
+            page(Some("A"), None),
+            page(Some("B"), None),
+        ];
+        let tags = Accessibility::default().meta_tags(&pages, EpubVersion::default());
+        assert_eq!(
+            values(&tags, "accessibilitySummary"),
+            ["Comic in fixed layout. Every page is an image with a text description."]
+        );
+        assert_eq!(values(&tags, "accessModeSufficient"), ["visual", "textual"]);
+
+        let pages = [page(None, None), page(Some("A"), None), page(None, None)];
+        let tags = Accessibility::default().meta_tags(&pages, EpubVersion::default());
+        assert_eq!(
+            values(&tags, "accessibilitySummary"),
+            ["Comic in fixed layout. The pages are images, and not every page has a text description."]
+        );
+        assert_eq!(values(&tags, "accessModeSufficient"), ["visual"]);
+    }
+
+    #[test]
+    fn metadata_json_overrides_the_defaults() -> Result<()> {
+        let metadata: Metadata = serde_json::from_str(
+            r#"{
+                "title": "Test",
+                "is_rtl": false,
+                "accessibility": {
+                    "hazards": ["flashing"],
+                    "summary": "Some pages flash & are described."
+                }
+            }"#,
+        )?;
+        let pages = [page(None, None), page(Some("A"), None)];
+        let tags = metadata
+            .accessibility
+            .meta_tags(&pages, EpubVersion::default());
+        assert_eq!(values(&tags, "accessibilityHazard"), ["flashing"]);
+        assert_eq!(
+            values(&tags, "accessibilitySummary"),
+            ["Some pages flash &amp; are described."]
+        );
+        // What is not given is still filled in from the pages
+        assert_eq!(
+            values(&tags, "accessibilityFeature"),
+            ["tableOfContents", "alternativeText"]
+        );
+
+        let tags = metadata.accessibility.meta_tags(&pages, EpubVersion::Epub2);
+        assert!(tags.contains(r#"<meta name="schema:accessibilityHazard" content="flashing"/>"#));
+        Ok(())
+    }
+
+    #[test]
+    fn defaults_are_not_read_back_as_given() {
+        let pages = [page(None, None), page(Some("A"), None)];
+        let completed = Accessibility::default().completed(&pages);
+        assert_eq!(completed.without_defaults(&pages), Accessibility::default());
+
+        let given = Accessibility {
+            hazards: vec!["flashing".to_string()],
+            ..Accessibility::default()
+        };
+        let completed = given.completed(&pages);
+        assert_eq!(completed.without_defaults(&pages), given);
+    }
+
+    #[test]
+    fn alt_text_falls_back_to_the_page_label() {
+        assert_eq!(page(Some("A"), Some("7")).alt_text(), "A");
+        assert_eq!(page(None, Some("7")).alt_text(), "Page 7");
+        assert_eq!(page(None, None).alt_text(), "");
+    }
+}
@@ -0,0 +1,321 @@
+use std::{
+    fs::{copy, create_dir_all, read, read_dir, remove_file, rename, File},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{bail, Result};
+use uuid::Uuid;
+
+use super::images::{padding_image_file, Image, PageFormat, PageOptions};
+
+/// Bumped whenever the processing of pages changes, so that pages cached by
+/// an older version are not reused.
+const CACHE_VERSION: u32 = 3;
+
+/// Processed pages kept on disk, addressed by the content of their source
+/// file and the options they were processed with, so that unchanged pages are
+/// not decoded and encoded again on later builds.
+pub struct PageCache {
+    dir: PathBuf,
+}
+
+/// Result of [`PageCache::prune`].
+pub struct PruneSummary {
+    pub removed: usize,
+    pub freed: u64,
+}
+
+impl PageCache {
+    /// # Errors
+    ///
+    /// Returns an error if the cache directory cannot be created.
+    pub fn new(dir: &str) -> Result<Self> {
+        create_dir_all(dir)?;
+        Ok(Self {
+            dir: PathBuf::from(dir),
+        })
+    }
+
+    /// Opens an existing cache directory, without creating it as
+    /// [`PageCache::new`] does, so that a mistyped path is reported.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `dir` is not a directory.
+    pub fn open(dir: &str) -> Result<Self> {
+        if !Path::new(dir).is_dir() {
+            bail!("cache directory not found: {dir}");
+        }
+        Ok(Self {
+            dir: PathBuf::from(dir),
+        })
+    }
+
+    /// Identifies a processed page by its source content, the canvas it is
+    /// padded to, the options it is processed with and its format.
+    fn key(
+        image: &Image,
+        max_width: u32,
+        max_height: u32,
+        options: &PageOptions,
+        format: PageFormat,
+    ) -> Result<String> {
+        Self::versioned_key(CACHE_VERSION, image, max_width, max_height, options, format)
+    }
+
+    fn versioned_key(
+        version: u32,
+        image: &Image,
+        max_width: u32,
+        max_height: u32,
+        options: &PageOptions,
+        format: PageFormat,
+    ) -> Result<String> {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&read(&image.path)?);
+        hasher.update(
+            format!("v{version};{max_width}x{max_height};{options:?};{format:?}").as_bytes(),
+        );
+        Ok(hasher.finalize().to_hex().to_string())
+    }
+
+    /// Whether `path` is named like an entry: a key and the extension of a
+    /// page format.
+    fn is_entry(path: &Path) -> bool {
+        let is_key = path
+            .file_stem()
+            .and_then(|x| x.to_str())
+            .is_some_and(|x| x.len() == 64 && x.bytes().all(|c| c.is_ascii_hexdigit()));
+        let is_page = path.extension().is_some_and(|x| x == "webp" || x == "jpg");
+        is_key && is_page
+    }
+
+    /// Same as [`padding_image_file`], but reuses the page processed by a
+    /// previous build if there is one.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the image cannot be processed or copied.
+    pub fn padding_image_file(
+        &self,
+        image_file: &Image,
+        max_width: u32,
+        max_height: u32,
+        out_path: &str,
+        options: &PageOptions,
+        format: PageFormat,
+    ) -> Result<()> {
+        let cached = self.dir.join(format!(
+            "{}.{}",
+            Self::key(image_file, max_width, max_height, options, format)?,
+            format.extension()
+        ));
+        if cached.exists() {
+            // Mark the entry as recently used for pruning
+            File::options()
+                .write(true)
+                .open(&cached)?
+                .set_modified(SystemTime::now())?;
+        } else {
+            // Write to a temporary file first so that concurrent builds never
+            // see a partially written entry
+            let tmp = self
+                .dir
+                .join(format!("{}.tmp.{}", Uuid::new_v4(), format.extension()));
+            let result = padding_image_file(
+                image_file,
+                max_width,
+                max_height,
+                &tmp.to_string_lossy(),
+                options,
+                format,
+            )
+            .and_then(|_| Ok(rename(&tmp, &cached)?));
+            if result.is_err() {
+                let _ = remove_file(&tmp);
+            }
+            result?;
+        }
+        copy(&cached, out_path)?;
+        Ok(())
+    }
+
+    /// Removes entries not used for longer than `max_age`, then the least
+    /// recently used ones until the cache is no larger than `max_size`.
+    /// Everything is removed if neither limit is given. Files not named like
+    /// entries are left alone.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the cache directory cannot be read or an entry cannot be removed.
+    pub fn prune(&self, max_age: Option<Duration>, max_size: Option<u64>) -> Result<PruneSummary> {
+        let mut entries = Vec::new();
+        for entry in read_dir(&self.dir)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if metadata.is_file() && Self::is_entry(&entry.path()) {
+                entries.push((entry.path(), metadata.modified()?, metadata.len()));
+            }
+        }
+        // Most recently used first
+        entries.sort_by_key(|x| std::cmp::Reverse(x.1));
+
+        let now = SystemTime::now();
+        let mut kept_size = 0;
+        let mut summary = PruneSummary {
+            removed: 0,
+            freed: 0,
+        };
+        for (path, modified, len) in entries {
+            let expired =
+                max_age.is_some_and(|x| now.duration_since(modified).unwrap_or_default() > x);
+            let overflows = max_size.is_some_and(|x| kept_size + len > x);
+            if expired || overflows || (max_age.is_none() && max_size.is_none()) {
+                remove_file(&path)?;
+                summary.removed += 1;
+                summary.freed += len;
+            } else {
+                kept_size += len;
+            }
+        }
+
+        Ok(summary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::write;
+
+    use super::*;
+    use crate::epub::testing::TestDir;
+
+    const DAY: Duration = Duration::from_hours(24);
+
+    /// Writes an entry of `len` bytes last used `age` ago.
+    fn entry(dir: &TestDir, key: char, len: usize, age: Duration) -> Result<PathBuf> {
+        let path = dir.path(&format!("{}.webp", key.to_string().repeat(64)));
+        write(&path, vec![0; len])?;
+        File::options()
+            .write(true)
+            .open(&path)?
+            .set_modified(SystemTime::now() - age)?;
+        Ok(path)
+    }
+
+    #[test]
+    fn old_entries_are_pruned() -> Result<()> {
+        let dir = TestDir::new(&[])?;
+        let old = entry(&dir, 'a', 10, 40 * DAY)?;
+        let recent = entry(&dir, 'b', 10, DAY)?;
+        let summary = PageCache::open(&dir.0)?.prune(Some(30 * DAY), None)?;
+        assert_eq!((summary.removed, summary.freed), (1, 10));
+        assert!(!old.exists());
+        assert!(recent.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn least_recently_used_entries_are_pruned_to_the_size() -> Result<()> {
+        let dir = TestDir::new(&[])?;
+        let oldest = entry(&dir, 'a', 40, 3 * DAY)?;
+        let older = entry(&dir, 'b', 40, 2 * DAY)?;
+        let newest = entry(&dir, 'c', 40, DAY)?;
+        let summary = PageCache::open(&dir.0)?.prune(None, Some(100))?;
+        assert_eq!((summary.removed, summary.freed), (1, 40));
+        assert!(!oldest.exists());
+        assert!(older.exists());
+        assert!(newest.exists());
+
+        let summary = PageCache::open(&dir.0)?.prune(None, None)?;
+        assert_eq!(summary.removed, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn other_files_are_not_pruned() -> Result<()> {
+        let dir = TestDir::new(&[])?;
+        let cached = entry(&dir, 'f', 10, DAY)?;
+        let others = ["thesis.docx", "notes.webp", "0123.jpg"].map(|x| dir.path(x));
+        for path in &others {
+            write(path, "keep")?;
+        }
+        let summary = PageCache::open(&dir.0)?.prune(None, None)?;
+        assert_eq!(summary.removed, 1);
+        assert!(!cached.exists());
+        assert!(others.iter().all(|x| x.exists()));
+        Ok(())
+    }
+
+    #[test]
+    fn missing_directory_is_not_created_by_prune() {
+        let dir = format!("./target/test-{}", Uuid::new_v4());
+        assert!(PageCache::open(&dir).is_err());
+        assert!(!Path::new(&dir).exists());
+    }
+
+    #[test]
+    fn key_changes_with_options_and_version() -> Result<()> {
+        let dir = TestDir::new(&["a.png"])?;
+        let image = Image {
+            path: dir.path("a.png"),
+            ..Image::default()
+        };
+        let options = PageOptions::default();
+        let key = |options: &PageOptions, format| PageCache::key(&image, 40, 60, options, format);
+        let base = key(&options, PageFormat::Webp)?;
+        assert_eq!(base, key(&options, PageFormat::Webp)?);
+        assert!(PageCache::is_entry(Path::new(&format!("{base}.webp"))));
+
+        let shrunk = PageOptions {
+            max_dimension: Some(20),
+            ..PageOptions::default()
+        };
+        assert_ne!(base, key(&shrunk, PageFormat::Webp)?);
+        assert_ne!(base, key(&options, PageFormat::Jpeg(90))?);
+        assert_ne!(
+            key(&options, PageFormat::Jpeg(90))?,
+            key(&options, PageFormat::Jpeg(80))?
+        );
+        assert_ne!(
+            base,
+            PageCache::key(&image, 80, 60, &options, PageFormat::Webp)?
+        );
+        assert_ne!(
+            base,
+            PageCache::versioned_key(
+                CACHE_VERSION + 1,
+                &image,
+                40,
+                60,
+                &options,
+                PageFormat::Webp
+            )?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn failed_pages_leave_no_temporary_file() -> Result<()> {
+        let dir = TestDir::new(&[])?;
+        write(dir.path("broken.png"), "not an image")?;
+        let cache = PageCache::new(&dir.path("cache").to_string_lossy())?;
+        let image = Image {
+            path: dir.path("broken.png"),
+            ..Image::default()
+        };
+        let out = dir.path("out.webp").to_string_lossy().into_owned();
+        let result = cache.padding_image_file(
+            &image,
+            40,
+            60,
+            &out,
+            &PageOptions::default(),
+            PageFormat::Webp,
+        );
+        assert!(result.is_err());
+        assert_eq!(read_dir(dir.path("cache"))?.count(), 0);
+        Ok(())
+    }
+}
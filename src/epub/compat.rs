@@ -0,0 +1,332 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::write,
+    str::FromStr,
+};
+
+use anyhow::{bail, Result};
+
+use super::{
+    converter::{escape_xml, Chapter, Landmark, Metadata},
+    images::{Image, PageFormat},
+};
+
+/// Which reading systems a book is written for.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EpubVersion {
+    /// EPUB 3
+    #[default]
+    Epub3,
+    /// EPUB 3 that also carries the NCX table of contents, the guide and the
+    /// cover metadata of EPUB 2, so that older readers can open it too
+    Compatible,
+    /// Strict EPUB 2.0.1, without any EPUB 3 feature
+    Epub2,
+}
+
+impl FromStr for EpubVersion {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "3" => Ok(Self::Epub3),
+            "compat" => Ok(Self::Compatible),
+            "2" => Ok(Self::Epub2),
+            _ => bail!("invalid EPUB version: {s} (expected 3, compat or 2)"),
+        }
+    }
+}
+
+impl EpubVersion {
+    /// Whether the book has the EPUB 3 navigation document and properties.
+    #[must_use]
+    pub fn is_epub3(self) -> bool {
+        self != Self::Epub2
+    }
+
+    /// Whether the book has the NCX table of contents and the guide.
+    #[must_use]
+    pub fn is_legacy(self) -> bool {
+        self != Self::Epub3
+    }
+
+    /// Format of the page images. WebP is not a core media type of EPUB 2,
+    /// so its readers get JPEG.
+    #[must_use]
+    pub fn page_format(self) -> PageFormat {
+        if self.is_epub3() {
+            PageFormat::Webp
+        } else {
+            PageFormat::Jpeg(PageFormat::JPEG_QUALITY)
+        }
+    }
+
+    /// Document type declaration of the XHTML content documents.
+    #[must_use]
+    pub fn doctype(self) -> &'static str {
+        if self.is_epub3() {
+            "<!DOCTYPE html>"
+        } else {
+            r#"<!DOCTYPE html PUBLIC "-//W3C//DTD XHTML 1.1//EN" "http://www.w3.org/TR/xhtml11/DTD/xhtml11.dtd">"#
+        }
+    }
+}
+
+/// Tags the book as a volume of its series the way EPUB 2 readers (and
+/// Calibre) understand.
+pub fn calibre_series_meta_tags(metadata: &Metadata) -> String {
+    let Some(series) = &metadata.series else {
+        return String::new();
+    };
+    let index = metadata.volume.map_or(String::new(), |x| {
+        format!(
+            r#"
+        <meta name="calibre:series_index" content="{x}"/>"#
+        )
+    });
+    format!(
+        r#"
+        <meta name="calibre:series" content="{}"/>{index}"#,
+        escape_xml(series)
+    )
+}
+
+/// The guide of EPUB 2, pointing at the same places as the landmarks. Back
+/// matter has no type of its own in the guide and is left out.
+pub fn guide(landmarks: &[Landmark]) -> String {
+    let references = landmarks
+        .iter()
+        .filter_map(|x| {
+            let kind = match x.epub_type {
+                "cover" => "cover",
+                "titlepage" => "title-page",
+                "bodymatter" => "text",
+                _ => return None,
+            };
+            Some(format!(
+                r#"
+        <reference type="{kind}" title="{}" href="part{}.xhtml"/>"#,
+                x.title, x.part
+            ))
+        })
+        .collect::<Vec<_>>()
+        .concat();
+    if references.is_empty() {
+        return String::new();
+    }
+    format!(
+        r"
+    <guide>{references}
+    </guide>"
+    )
+}
+
+/// Whether `label` is a roman numeral, the way front matter is numbered.
+fn is_roman_numeral(label: &str) -> bool {
+    !label.is_empty()
+        && label
+            .chars()
+            .all(|c| "ivxlcdm".contains(c.to_ascii_lowercase()))
+}
+
+/// Writes `toc.ncx`, the table of contents of EPUB 2, with the chapters and
+/// the printed page numbers.
+///
+/// # Errors
+///
+/// Returns an error if writing the file fails.
+pub fn create_ncx_file(
+    dir: &str,
+    identifier: &str,
+    title: &str,
+    chapters: &[Chapter],
+    images_files: &[Image],
+) -> Result<()> {
+    let labels = images_files
+        .iter()
+        .enumerate()
+        .filter_map(|(n, x)| x.label.as_ref().map(|label| (n, label)))
+        .collect::<Vec<_>>();
+
+    // Entries pointing at the same part share their place in the play order
+    let play_order = chapters
+        .iter()
+        .map(|x| x.start)
+        .chain(labels.iter().map(|(n, _)| *n))
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .enumerate()
+        .map(|(i, n)| (n, i + 1))
+        .collect::<BTreeMap<_, _>>();
+
+    let nav_points = chapters
+        .iter()
+        .enumerate()
+        .map(|(i, x)| {
+            format!(
+                r#"
+        <navPoint id="navpoint-{}" playOrder="{}">
+            <navLabel><text>{}</text></navLabel>
+            <content src="part{}.xhtml"/>
+        </navPoint>"#,
+                i + 1,
+                play_order[&x.start],
+                escape_xml(&x.title),
+                x.start
+            )
+        })
+        .collect::<Vec<_>>()
+        .concat();
+
+    let page_targets = labels
+        .iter()
+        .map(|(n, label)| {
+            let (kind, value) = match label.parse::<u32>() {
+                Ok(x) if x > 0 => ("normal", format!(r#" value="{x}""#)),
+                _ if is_roman_numeral(label) => ("front", String::new()),
+                _ => ("special", String::new()),
+            };
+            format!(
+                r#"
+        <pageTarget id="page-{n}" type="{kind}"{value} playOrder="{}">
+            <navLabel><text>{}</text></navLabel>
+            <content src="part{n}.xhtml"/>
+        </pageTarget>"#,
+                play_order[n],
+                escape_xml(label)
+            )
+        })
+        .collect::<Vec<_>>()
+        .concat();
+    let page_list = if page_targets.is_empty() {
+        String::new()
+    } else {
+        format!(
+            r"
+    <pageList>
+        <navLabel><text>Pages</text></navLabel>{page_targets}
+    </pageList>"
+        )
+    };
+
+    let max_page = labels
+        .iter()
+        .filter_map(|(_, x)| x.parse::<u32>().ok())
+        .max()
+        .unwrap_or(0);
+    write(
+        format!("{dir}/OEBPS/toc.ncx"),
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8" ?>
+<!DOCTYPE ncx PUBLIC "-//NISO//DTD ncx 2005-1//EN" "http://www.daisy.org/z3986/2005/ncx-2005-1.dtd">
+<ncx xmlns="http://www.daisy.org/z3986/2005/ncx/" version="2005-1">
+    <head>
+        <meta name="dtb:uid" content="{identifier}"/>
+        <meta name="dtb:depth" content="1"/>
+        <meta name="dtb:totalPageCount" content="{}"/>
+        <meta name="dtb:maxPageNumber" content="{max_page}"/>
+    </head>
+    <docTitle><text>{}</text></docTitle>
+    <navMap>{nav_points}
+    </navMap>{page_list}
+</ncx>"#,
+            labels.len(),
+            escape_xml(title)
+        ),
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir_all, read_to_string};
+
+    use super::*;
+    use crate::epub::testing::TestDir;
+
+    fn page(label: Option<&str>) -> Image {
+        Image {
+            label: label.map(String::from),
+            ..Image::default()
+        }
+    }
+
+    #[test]
+    fn guide_points_at_the_landmarks() {
+        let landmarks = [
+            Landmark {
+                epub_type: "cover",
+                part: 0,
+                title: "Cover",
+            },
+            Landmark {
+                epub_type: "bodymatter",
+                part: 1,
+                title: "Start",
+            },
+            Landmark {
+                epub_type: "backmatter",
+                part: 5,
+                title: "Afterword",
+            },
+        ];
+        assert_eq!(
+            guide(&landmarks),
+            r#"
+    <guide>
+        <reference type="cover" title="Cover" href="part0.xhtml"/>
+        <reference type="text" title="Start" href="part1.xhtml"/>
+    </guide>"#
+        );
+        assert_eq!(guide(&landmarks[2..]), "");
+    }
+
+    #[test]
+    fn ncx_lists_chapters_and_page_numbers() -> Result<()> {
+        let dir = TestDir::new(&[])?;
+        create_dir_all(dir.path("OEBPS"))?;
+        let pages = [
+            page(None),
+            page(Some("ii")),
+            page(Some("")),
+            page(Some("1")),
+            page(Some("Extra")),
+        ];
+        let chapters = [
+            Chapter {
+                title: "One & Two".to_string(),
+                start: 1,
+            },
+            Chapter {
+                title: "Three".to_string(),
+                start: 3,
+            },
+        ];
+        create_ncx_file(&dir.0, "urn:uuid:test", "Test", &chapters, &pages)?;
+        let ncx = read_to_string(dir.path("OEBPS/toc.ncx"))?;
+
+        assert!(ncx.contains(r#"<meta name="dtb:totalPageCount" content="4"/>"#));
+        assert!(ncx.contains(r#"<meta name="dtb:maxPageNumber" content="1"/>"#));
+        assert!(ncx.contains(
+            r#"<navPoint id="navpoint-1" playOrder="1">
+            <navLabel><text>One &amp; Two</text></navLabel>
+            <content src="part1.xhtml"/>"#
+        ));
+        // A chapter and a page number at the same part share their place
+        assert!(ncx.contains(r#"<navPoint id="navpoint-2" playOrder="3">"#));
+        assert!(ncx.contains(r#"<pageTarget id="page-1" type="front" playOrder="1">"#));
+        assert!(ncx.contains(r#"<pageTarget id="page-2" type="special" playOrder="2">"#));
+        assert!(ncx.contains(r#"<pageTarget id="page-3" type="normal" value="1" playOrder="3">"#));
+        assert!(ncx.contains(r#"<pageTarget id="page-4" type="special" playOrder="4">"#));
+        Ok(())
+    }
+
+    #[test]
+    fn only_roman_numerals_are_front_matter() {
+        assert!(is_roman_numeral("iv"));
+        assert!(is_roman_numeral("XII"));
+        assert!(!is_roman_numeral(""));
+        assert!(!is_roman_numeral("A1"));
+    }
+}
@@ -1,15 +1,24 @@
-use super::images::Image;
-use anyhow::{anyhow, Result};
+use super::{
+    accessibility::Accessibility,
+    compat::{calibre_series_meta_tags, guide, EpubVersion},
+    generated::{text_page, title_page_body, GeneratedPage},
+    images::{Image, PageFormat, PageOptions},
+    pages::{PageEntry, PageSpread},
+    panels::panel_markup,
+    rendition::Rendition,
+    vendor::Vendor,
+};
+use anyhow::{anyhow, bail, Result};
 use epub::doc::EpubDoc;
 use serde::Deserialize;
 use std::{
     env,
-    fs::{create_dir, remove_dir_all, write},
+    fs::{create_dir, read_dir, remove_dir_all, write},
     path::Path,
     process::Command,
 };
 
-#[derive(Deserialize)]
+#[derive(Clone, Deserialize)]
 pub struct Metadata {
     pub title: String,
     pub creator: Option<String>,
@@ -17,6 +26,21 @@ pub struct Metadata {
     pub date: Option<String>,
     pub is_rtl: bool,
     pub blank: Option<bool>,
+    /// Name of the series the book belongs to
+    pub series: Option<String>,
+    /// Position of the book in its series, counted from 1
+    pub volume: Option<u32>,
+    /// Explicit reading order of the image files
+    pub pages: Option<Vec<PageEntry>>,
+    /// Image used as the cover, relative to the image directory
+    pub cover: Option<String>,
+    /// Orientation and spreads the book is read with
+    #[serde(default)]
+    pub rendition: Rendition,
+    /// Accessibility of the book; what is not given is filled in from the
+    /// pages
+    #[serde(default)]
+    pub accessibility: Accessibility,
 }
 
 impl Metadata {
@@ -46,6 +70,26 @@ impl Metadata {
     }
 }
 
+/// Escapes the characters that are not allowed verbatim in XML text and attribute values.
+pub fn escape_xml(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// Reverses [`escape_xml`].
+pub fn unescape_xml(value: &str) -> String {
+    value
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
 /// # Errors
 ///
 /// Returns an error if the directory or its contents cannot be removed.
@@ -61,7 +105,7 @@ pub fn rm_directory(dir: &str) -> Result<()> {
 /// # Errors
 ///
 /// Returns an error if any directory or file creation fails.
-pub fn initialize_directory(dir: &str) -> Result<()> {
+pub fn initialize_directory(dir: &str, vendor: Vendor, rendition: Rendition) -> Result<()> {
     // Create the directory and subdirectories
     create_dir(dir)?;
     create_dir(format!("{dir}/OEBPS"))?;
@@ -82,13 +126,159 @@ pub fn initialize_directory(dir: &str) -> Result<()> {
 </container>"#,
     )?;
 
+    // Create the reset.css file
+    write(
+        format!("{dir}/OEBPS/reset.css"),
+        r"html {color: #000; background: #FFF;}
+body,div,dl,dt,dd,ul,ol,li,h1,h2,h3,h4,h5,h6,th,td {margin: 0; padding: 0;}
+table {border-collapse: collapse; border-spacing: 0;}
+fieldset,img {border: 0;}
+caption,th,var {font-style: normal; font-weight: normal;}
+li {list-style: none;}
+caption,th {text-align: left;}
+h1,h2,h3,h4,h5,h6 {font-size: 100%; font-weight: normal;}
+sup {vertical-align: text-top;}
+sub {vertical-align: text-bottom;}
+a.app-amzn-magnify {display: block; width: 100%; height: 100%;}",
+    )?;
+
+    vendor.write_files(dir, rendition)?;
+
     Ok(())
 }
 
+/// An entry of the table of contents.
+pub struct Chapter {
+    pub title: String,
+    /// Index of the page the chapter starts at, where 0 is the cover
+    pub start: usize,
+}
+
+impl Chapter {
+    /// The table of contents of a single book, which only points at the cover.
+    pub fn cover() -> Vec<Chapter> {
+        vec![Chapter {
+            title: "表紙".to_string(),
+            start: 0,
+        }]
+    }
+}
+
+/// A place in the book readers can jump to.
+pub struct Landmark {
+    /// Structural semantics of the place, such as `bodymatter`
+    pub epub_type: &'static str,
+    /// Number of the part file it starts at
+    pub part: usize,
+    pub title: &'static str,
+}
+
+/// Points readers at the cover, the title page, the start of the content and
+/// the text pages that end the book, as far as the book has them. The content
+/// starts at the first page after the cover that is not one of the
+/// `front_matter` pages, the title page or the blank page.
+pub fn landmarks(images_files: &[Image], cover_page: bool, front_matter: usize) -> Vec<Landmark> {
+    let mut landmarks = Vec::new();
+    let mut push = |epub_type, part, title| {
+        landmarks.push(Landmark {
+            epub_type,
+            part,
+            title,
+        });
+    };
+    if cover_page {
+        push("cover", 0, "Cover");
+    }
+    let is_title_page = |x: &Image| x.generated == Some(GeneratedPage::TitlePage);
+    if let Some(n) = images_files.iter().skip(1).position(is_title_page) {
+        push("titlepage", n + 1, "Title page");
+    }
+    let body = images_files
+        .iter()
+        .enumerate()
+        .skip(front_matter + 1)
+        .find(|(_, x)| !is_title_page(x) && x.file_name != "blank")
+        .map(|(n, _)| n);
+    if let Some(n) = body {
+        push("bodymatter", n, "Start of content");
+    }
+    let back = images_files
+        .iter()
+        .rposition(|x| x.generated != Some(GeneratedPage::Text))
+        .map(|n| n + 1)
+        .filter(|&n| n < images_files.len() && body.is_none_or(|x| n > x));
+    if let Some(n) = back {
+        push("backmatter", n, "Back matter");
+    }
+    landmarks
+}
+
+/// Writes the navigation document: the table of contents, the landmarks of
+/// the book and, if any page has a label, the list of printed page numbers.
+///
 /// # Errors
 ///
-/// Returns an error if writing the nav or CSS files fails.
-pub fn create_nav_file(dir: &str, width: u32, height: u32) -> Result<()> {
+/// Returns an error if writing the nav file fails.
+pub fn create_nav_file(
+    dir: &str,
+    width: u32,
+    height: u32,
+    chapters: &[Chapter],
+    images_files: &[Image],
+    landmarks: &[Landmark],
+) -> Result<()> {
+    let toc_items = chapters
+        .iter()
+        .map(|x| {
+            format!(
+                r#"<li>
+                    <a href="part{}.xhtml">{}</a>
+                </li>"#,
+                x.start,
+                escape_xml(&x.title)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n                ");
+
+    // Printed page numbers, if any page has one
+    let page_items = images_files
+        .iter()
+        .enumerate()
+        .filter_map(|(n, image)| {
+            image.label.as_ref().map(|label| {
+                format!(
+                    r#"<li><a href="part{n}.xhtml">{}</a></li>"#,
+                    escape_xml(label)
+                )
+            })
+        })
+        .collect::<Vec<_>>();
+    let page_list = if page_items.is_empty() {
+        String::new()
+    } else {
+        format!(
+            r#"
+        <nav epub:type="page-list" hidden="">
+            <ol>
+                {}
+            </ol>
+        </nav>"#,
+            page_items.join("\n                ")
+        )
+    };
+
+    let landmark_items = landmarks
+        .iter()
+        .map(|x| {
+            format!(
+                r#"<li><a epub:type="{}" href="part{}.xhtml">{}</a></li>"#,
+                x.epub_type, x.part, x.title
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n                ");
+
     // Create the nav.xhtml file
     write(
         format!("{dir}/OEBPS/nav.xhtml"),
@@ -104,41 +294,175 @@ pub fn create_nav_file(dir: &str, width: u32, height: u32) -> Result<()> {
         <nav epub:type="toc" hidden="">
             <h1>Table of contents</h1>
             <ol>
-                <li>
-                    <a href="part0.xhtml">表紙</a>
-                </li>
+                {toc_items}
             </ol>
         </nav>
+        <nav epub:type="landmarks" hidden="">
+            <h1>Landmarks</h1>
+            <ol>
+                {landmark_items}
+            </ol>
+        </nav>{page_list}
     </body>
 </html>"#,
         ),
     )?;
 
-    // Create the reset.css file
-    write(
-        format!("{dir}/OEBPS/reset.css"),
-        r"html {color: #000; background: #FFF;}
-body,div,dl,dt,dd,ul,ol,li,h1,h2,h3,h4,h5,h6,th,td {margin: 0; padding: 0;}
-table {border-collapse: collapse; border-spacing: 0;}
-fieldset,img {border: 0;}
-caption,th,var {font-style: normal; font-weight: normal;}
-li {list-style: none;}
-caption,th {text-align: left;}
-h1,h2,h3,h4,h5,h6 {font-size: 100%; font-weight: normal;}
-sup {vertical-align: text-top;}
-sub {vertical-align: text-bottom;}
-a.app-amzn-magnify {display: block; width: 100%; height: 100%;}",
-    )?;
-
     Ok(())
 }
 
+/// Tags the book as a volume of its series.
+fn series_meta_tags(metadata: &Metadata) -> String {
+    let Some(series) = &metadata.series else {
+        return String::new();
+    };
+    let position = metadata.volume.map_or(String::new(), |x| {
+        format!(
+            r##"
+        <meta refines="#series" property="group-position">{x}</meta>"##
+        )
+    });
+    format!(
+        r##"
+        <meta property="belongs-to-collection" id="series">{}</meta>
+        <meta refines="#series" property="collection-type">series</meta>{position}"##,
+        escape_xml(series)
+    )
+}
+
+/// Describes each merged volume as a part of the book, refined with its own metadata.
+fn volume_meta_tags(volumes: &[Metadata]) -> String {
+    let tags = volumes
+        .iter()
+        .enumerate()
+        .flat_map(|(i, volume)| {
+            let id = format!("volume-{}", i + 1);
+            let refines = [
+                ("creator", &volume.creator),
+                ("publisher", &volume.publisher),
+                ("date", &volume.date),
+            ]
+            .into_iter()
+            .filter_map(|(property, value)| {
+                value.as_ref().map(|x| {
+                    format!(
+                        r##"<meta refines="#{id}" property="dcterms:{property}">{}</meta>"##,
+                        escape_xml(x)
+                    )
+                })
+            })
+            .collect::<Vec<_>>();
+            std::iter::once(format!(
+                r#"<meta property="dcterms:hasPart" id="{id}">{}</meta>"#,
+                escape_xml(&volume.title)
+            ))
+            .chain(refines)
+        })
+        .collect::<Vec<_>>();
+    if tags.is_empty() {
+        String::new()
+    } else {
+        format!("\n        {}", tags.join("\n        "))
+    }
+}
+
+fn manifest_items(images_files: &[Image], format: PageFormat) -> String {
+    images_files
+        .iter()
+        .skip(1)
+        .enumerate()
+        .flat_map(|(i, x)| {
+            let n = i + 1;
+            let mut items = vec![format!(
+                r#"<item id="part{n}" href="part{n}.xhtml" media-type="application/xhtml+xml"/>"#
+            )];
+            if x.has_image() {
+                items.push(format!(
+                    r#"<item id="image-{}" href="{}" media-type="{}"/>"#,
+                    x.file_name,
+                    x.relative_path(format),
+                    x.media_type(format)
+                ));
+            }
+            items
+        })
+        .collect::<Vec<_>>()
+        .join("\n        ")
+}
+
+/// Pages alternate sides starting from the one the book opens on; a page
+/// forced onto a side restarts the alternation from there, and a centered
+/// page is followed by a fresh spread. Reflowing pages, such as text pages,
+/// take no side and are followed by a fresh spread too. Pages overriding the
+/// rendition of the book carry those properties as well. EPUB 2 has none of
+/// these properties.
+fn spine_items(
+    images_files: &[Image],
+    is_rtl: bool,
+    version: EpubVersion,
+    vendor: Vendor,
+) -> String {
+    let first = if is_rtl {
+        PageSpread::Right
+    } else {
+        PageSpread::Left
+    };
+    let mut next = first;
+    images_files
+        .iter()
+        .enumerate()
+        .skip(1)
+        .map(|(n, image)| {
+            if !version.is_epub3() {
+                return format!(r#"<itemref idref="part{n}"/>"#);
+            }
+            let mut properties = Vec::new();
+            if image.rendition.is_reflowable(image.generated) {
+                next = first;
+            } else {
+                let spread = image.spread.unwrap_or(next);
+                next = match spread {
+                    PageSpread::Left => PageSpread::Right,
+                    PageSpread::Right => PageSpread::Left,
+                    PageSpread::Center => first,
+                };
+                properties.push(match spread {
+                    PageSpread::Left => "page-spread-left",
+                    PageSpread::Right => "page-spread-right",
+                    PageSpread::Center => vendor.center_property(),
+                });
+            }
+            let overrides = image.rendition.properties(image.generated);
+            for property in &overrides {
+                // A centered page on Kobo may already be shown on its own
+                if !properties.contains(&property.as_str()) {
+                    properties.push(property);
+                }
+            }
+            format!(
+                r#"<itemref idref="part{n}" properties="{}"/>"#,
+                properties.join(" ")
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n        ")
+}
+
 pub struct OpfParams<'a> {
     pub identifier: &'a str,
     pub language: &'a str,
     pub modified: &'a str,
     pub max_width: u32,
     pub max_height: u32,
+    /// Metadata of the volumes merged into the book, if any
+    pub volumes: &'a [Metadata],
+    /// Whether the cover is shown as a page of its own (`part0`)
+    pub cover_page: bool,
+    pub version: EpubVersion,
+    pub vendor: Vendor,
+    pub landmarks: &'a [Landmark],
+    /// Options the pages were processed with
+    pub page_options: &'a PageOptions,
 }
 
 /// # Errors
@@ -153,161 +477,313 @@ pub fn create_opf_file(
     let OpfParams {
         identifier,
         language,
-        modified,
         max_width,
         max_height,
+        cover_page,
+        version,
+        vendor,
+        landmarks,
+        page_options,
+        ..
     } = params;
+    let epub3 = version.is_epub3();
     // Create the content.opf file
-    let creator_tag = metadata
-        .creator
-        .as_ref()
-        .map_or(String::new(), |x| format!(r"<dc:creator>{x}</dc:creator>"));
-    let publisher_tag = metadata
-        .publisher
-        .as_ref()
-        .map_or(String::new(), |x| format!(r"<dc:publisher>{x}</dc:publisher>"));
-    let date_tag = metadata
-        .date
-        .as_ref()
-        .map_or(String::new(), |x| format!(r"<dc:date>{x}</dc:date>"));
-    let rtl_meta = if metadata.is_rtl {
-        r#"<meta name="primary-writing-mode" content="horizontal-rl"/>"#
+    let dc_tags = [
+        ("creator", &metadata.creator),
+        ("publisher", &metadata.publisher),
+        ("date", &metadata.date),
+    ]
+    .into_iter()
+    .filter_map(|(name, value)| {
+        value
+            .as_ref()
+            .map(|x| format!(r"<dc:{name}>{}</dc:{name}>", escape_xml(x)))
+    })
+    .collect::<Vec<_>>()
+    .concat();
+    let version_metas = version_meta_tags(params, metadata);
+    let accessibility_metas = metadata.accessibility.meta_tags(images_files, *version);
+    let vendor_metas = vendor.meta_tags(
+        (*max_width, *max_height),
+        metadata,
+        epub3,
+        images_files.iter().any(|x| !x.panels.is_empty()),
+    );
+    let page_options_metas = page_options_meta_tags(page_options);
+    let format = page_options.page_format(*version);
+    let manifest_items = manifest_items(images_files, format);
+    let spine_direction = if metadata.is_rtl && epub3 {
+        r#" page-progression-direction="rtl""#
     } else {
         ""
     };
-    let manifest_items = images_files
-        .iter()
-        .skip(1)
-        .enumerate()
-        .flat_map(|(i, x)| {
-            let n = i + 1;
-            vec![
-                format!(r#"<item id="part{n}" href="part{n}.xhtml" media-type="application/xhtml+xml"/>"#),
-                format!(
-                    r#"<item id="image-{}" href="{}" media-type="image/webp"/>"#,
-                    x.file_name,
-                    x.relative_path()
-                ),
-            ]
-        })
-        .collect::<Vec<_>>()
-        .join("\n        ");
-    let spine_direction = if metadata.is_rtl {
-        r#" page-progression-direction="rtl""#
+    let spine_items = spine_items(images_files, metadata.is_rtl, *version, *vendor);
+    let (cover_item, cover_itemref) = cover_items(images_files, *cover_page, *version, format)?;
+    let (nav_item, nav_itemref) = if epub3 {
+        (
+            r#"
+        <item id="nav" href="nav.xhtml" media-type="application/xhtml+xml" properties="nav"/>"#,
+            r#"
+        <itemref idref="nav"/>"#,
+        )
     } else {
-        ""
+        ("", "")
+    };
+    let (ncx_item, spine_toc, guide) = if version.is_legacy() {
+        (
+            r#"
+        <item id="ncx" href="toc.ncx" media-type="application/x-dtbncx+xml"/>"#,
+            r#" toc="ncx""#,
+            guide(landmarks),
+        )
+    } else {
+        ("", "", String::new())
     };
-    let spine_items = images_files
-        .iter()
-        .skip(1)
-        .enumerate()
-        .map(|(i, _)| {
-            let n = i + 1;
-            let spread = if i % 2 == usize::from(metadata.is_rtl) {
-                "left"
-            } else {
-                "right"
-            };
-            format!(r#"<itemref idref="part{n}" properties="page-spread-{spread}"/>"#)
-        })
-        .collect::<Vec<_>>()
-        .join("\n        ");
 
     write(
         format!("{dir}/OEBPS/content.opf"),
         format!(
             r#"<?xml version="1.0" encoding="UTF-8" ?>
-<package xmlns="http://www.idpf.org/2007/opf" version="3.0" unique-identifier="pub-id">
+<package xmlns="http://www.idpf.org/2007/opf" version="{}" unique-identifier="pub-id">
     <metadata xmlns:opf="http://www.idpf.org/2007/opf" xmlns:dc="http://purl.org/dc/elements/1.1/">
         <dc:identifier id="pub-id">{identifier}</dc:identifier>
         <dc:title>{}</dc:title>
-        <dc:language>{language}</dc:language>{creator_tag}{publisher_tag}{date_tag}
-        <meta property="dcterms:modified">{modified}</meta>
-        <meta property="rendition:layout">pre-paginated</meta>
-        <meta name="fixed-layout" content="true"/>
-        <meta name="book-type" content="comic"/>
-        <meta property="rendition:orientation">auto</meta>
-        <meta property="rendition:spread">landscape</meta>
-        <meta name="orientation-lock" content="auto"/>
-        <meta name="original-resolution" content="{max_width}x{max_height}"/>{rtl_meta}
+        <dc:language>{language}</dc:language>{dc_tags}{version_metas}{accessibility_metas}{vendor_metas}{page_options_metas}
     </metadata>
-    <manifest>
-        <item id="nav" href="nav.xhtml" media-type="application/xhtml+xml" properties="nav"/>
-        <item id="part0" href="part0.xhtml" media-type="application/xhtml+xml"/>
-        <item id="cover" href="images/cover.webp" properties="cover-image" media-type="image/webp"/>
+    <manifest>{nav_item}{ncx_item}{cover_item}
         {manifest_items}
         <item href="reset.css" id="reset.css" media-type="text/css"/>
     </manifest>
-    <spine{spine_direction}>
-        <itemref idref="nav"/>
-        <itemref idref="part0" properties="rendition:spread-none"/>
+    <spine{spine_toc}{spine_direction}>{nav_itemref}{cover_itemref}
         {spine_items}
-    </spine>
-    <guide>
-        <reference type="cover" title="Cover" href="part0.xhtml"/>
-    </guide>
+    </spine>{guide}
 </package>"#,
-            metadata.title,
+            if epub3 { "3.0" } else { "2.0" },
+            escape_xml(&metadata.title),
         ),
     )?;
 
     Ok(())
 }
 
+/// Options the pages were processed with that decide how pages added to the
+/// book later look next to them.
+fn page_options_meta_tags(options: &PageOptions) -> String {
+    let native_size = if options.native_size {
+        r#"
+        <meta name="img2epub:native-size" content="true"/>"#
+    } else {
+        ""
+    };
+    format!(
+        r#"
+        <meta name="img2epub:background" content="{}"/>
+        <meta name="img2epub:padding" content="{}"/>{native_size}{}"#,
+        options.background,
+        options.padding,
+        options.quality.map_or(String::new(), |x| format!(
+            r#"
+        <meta name="img2epub:quality" content="{x}"/>"#
+        ))
+    )
+}
+
+/// Metadata only one of the versions understands: the EPUB 3 properties,
+/// and the cover and series as EPUB 2 readers know them.
+fn version_meta_tags(params: &OpfParams<'_>, metadata: &Metadata) -> String {
+    let epub3 = if params.version.is_epub3() {
+        format!(
+            r#"
+        <meta property="dcterms:modified">{}</meta>
+        <meta property="rendition:layout">pre-paginated</meta>
+        <meta property="rendition:orientation">{}</meta>
+        <meta property="rendition:spread">{}</meta>{}{}"#,
+            params.modified,
+            metadata.rendition.orientation.value(),
+            metadata.rendition.spread.value(),
+            series_meta_tags(metadata),
+            volume_meta_tags(params.volumes)
+        )
+    } else {
+        String::new()
+    };
+    let legacy = if params.version.is_legacy() {
+        format!(
+            r#"
+        <meta name="cover" content="cover"/>{}"#,
+            calibre_series_meta_tags(metadata)
+        )
+    } else {
+        String::new()
+    };
+    format!("{epub3}{legacy}")
+}
+
+/// Manifest items of the cover image and its page, and the spine item of the
+/// page if the cover is shown as a page of its own.
+fn cover_items(
+    images_files: &[Image],
+    cover_page: bool,
+    version: EpubVersion,
+    format: PageFormat,
+) -> Result<(String, String)> {
+    let cover = cover_image(images_files)?;
+    let (cover_properties, spread_properties) = if version.is_epub3() {
+        (
+            r#" properties="cover-image""#,
+            r#" properties="rendition:spread-none""#,
+        )
+    } else {
+        ("", "")
+    };
+    let image_item = format!(
+        r#"
+        <item id="cover" href="{}"{cover_properties} media-type="{}"/>"#,
+        cover.relative_path(format),
+        cover.media_type(format)
+    );
+    if !cover_page {
+        return Ok((image_item, String::new()));
+    }
+    Ok((
+        format!(
+            r#"
+        <item id="part0" href="part0.xhtml" media-type="application/xhtml+xml"/>{image_item}"#
+        ),
+        format!(
+            r#"
+        <itemref idref="part0"{spread_properties}/>"#
+        ),
+    ))
+}
+
+/// The first page as the cover image of the book, which is stored as `cover`.
+fn cover_image(image_files: &[Image]) -> Result<Image> {
+    let first = image_files
+        .first()
+        .ok_or_else(|| anyhow!("no pages to write"))?;
+    Ok(Image {
+        file_name: "cover".to_string(),
+        ..first.clone()
+    })
+}
+
+/// Writes a part file per page, each with a viewport of the page's own size.
+/// The cover gets `part0.xhtml` only if it is shown as a page of its own.
+/// A generated title page is written as text on the background of the
+/// pages, and a text page as reflowable XHTML. The documents are XHTML 1.1 in EPUB 2.
+///
 /// # Errors
 ///
 /// Returns an error if writing any part file fails.
 pub fn create_part_files(
     dir: &str,
-    title: &str,
+    metadata: &Metadata,
     image_files: &[Image],
-    max_width: u32,
-    max_height: u32,
+    cover_page: bool,
+    page_options: &PageOptions,
+    version: EpubVersion,
+    vendor: Vendor,
 ) -> Result<()> {
+    let title = escape_xml(&metadata.title);
+    let doctype = version.doctype();
+    let format = page_options.page_format(version);
+    let cover = cover_image(image_files)?;
+    let (cover_width, cover_height) = (cover.width, cover.height);
+
     // Create the part0.xhtml file
-    write(
-        format!("{dir}/OEBPS/part0.xhtml"),
-        format!(
-            r#"<?xml version="1.0" encoding="UTF-8" ?>
-<!DOCTYPE html>
+    if cover_page {
+        write(
+            format!("{dir}/OEBPS/part0.xhtml"),
+            format!(
+                r#"<?xml version="1.0" encoding="UTF-8" ?>
+{doctype}
 <html xmlns="http://www.w3.org/1999/xhtml">
     <head>
         <title>{title}</title>
-        <meta name="viewport" content="width={max_width}, height={max_height}"/>
+        <meta name="viewport" content="width={cover_width}, height={cover_height}"/>
         <link rel="stylesheet" type="text/css" href="reset.css"/>
     </head>
     <body style="font-size: 16px; height: 100%; text-align: center; width: 100%">
         {}
     </body>
 </html>"#,
-            format_args!(
-                r#"<img src="images/cover.webp" alt="cover.webp" style="height: {max_height}px; left: 0; position: absolute; top: 0; width: {max_width}px"/>"#,
-            )
-        ),
-    )?;
+                format_args!(
+                    r#"<img src="{}" alt="{}" style="height: {cover_height}px; left: 0; position: absolute; top: 0; width: {cover_width}px"/>"#,
+                    cover.relative_path(format),
+                    escape_xml(cover.alt.as_deref().unwrap_or("cover")),
+                )
+            ),
+        )?;
+    }
 
     // Create the partX.xhtml files
     for (i, file) in image_files.iter().skip(1).enumerate() {
         let n = i + 1;
+        let (width, height) = (file.width, file.height);
+        if file.generated == Some(GeneratedPage::TitlePage) {
+            write(
+                format!("{dir}/OEBPS/part{n}.xhtml"),
+                format!(
+                    r#"<?xml version="1.0" encoding="UTF-8" ?>
+{doctype}
+<html xmlns="http://www.w3.org/1999/xhtml"{}>
+    <head>
+        <title>{title}</title>
+        <meta name="viewport" content="width={width}, height={height}"/>
+        <link rel="stylesheet" type="text/css" href="reset.css"/>
+    </head>
+    {}
+</html>"#,
+                    if version.is_epub3() {
+                        r#" xmlns:epub="http://www.idpf.org/2007/ops""#
+                    } else {
+                        ""
+                    },
+                    title_page_body(metadata, width, height, page_options.background, version)
+                ),
+            )?;
+            continue;
+        }
+        if file.generated == Some(GeneratedPage::Text) {
+            write(
+                format!("{dir}/OEBPS/part{n}.xhtml"),
+                text_page(&file.path, &metadata.title, doctype)
+                    .map_err(|e| anyhow!("{}: {e}", file.path.display()))?,
+            )?;
+            continue;
+        }
+        let panels = panel_markup(
+            &file.panels,
+            (width, height),
+            &file.relative_path(format),
+            version,
+            vendor,
+        );
         write(
             format!("{dir}/OEBPS/part{n}.xhtml"),
             format!(
                 r#"<?xml version="1.0" encoding="UTF-8" ?>
-<!DOCTYPE html>
-<html xmlns="http://www.w3.org/1999/xhtml">
+{doctype}
+<html xmlns="http://www.w3.org/1999/xhtml"{}>
     <head>
         <title>{title}</title>
-        <meta name="viewport" content="width={max_width}, height={max_height}"/>
+        <meta name="viewport" content="width={width}, height={height}"/>
         <link rel="stylesheet" type="text/css" href="reset.css"/>
     </head>
     <body>
-        {}
+        {}{panels}
     </body>
 </html>"#,
+                if panels.is_empty() {
+                    ""
+                } else {
+                    r#" xmlns:epub="http://www.idpf.org/2007/ops""#
+                },
                 format_args!(
-                    r#"<img src="{}" alt="{}" style="height: {max_height}px; left: 0; position: absolute; top: 0; width: {max_width}px"/>"#,
-                    file.relative_path(),
-                    file.file_name,
+                    r#"<img src="{}" alt="{}" style="height: {height}px; left: 0; position: absolute; top: 0; width: {width}px"/>"#,
+                    file.relative_path(format),
+                    escape_xml(&file.alt_text()),
                 )
             ),
         )?;
@@ -351,17 +827,30 @@ pub fn zip_epub(dir: &str, out: &str) -> Result<()> {
 
     println!("{epub_dir} -> {out_path}");
 
-    Command::new("sh")
-        .arg("-c")
-        .arg(format!("cd {epub_dir} && zip -X0 {out_path} mimetype"))
-        .output()?;
-    Command::new("sh")
-        .arg("-c")
-        .arg(format!(
-            "cd {epub_dir} && zip -r9 {out_path} * -x mimetype -x .*"
-        ))
-        .output()?;
+    // The mimetype file must come first and be stored uncompressed
+    run_zip(&epub_dir, &["-X0", &out_path, "mimetype"])?;
+    let mut entries = read_dir(&epub_dir)?
+        .map(|x| Ok(x?.file_name().to_string_lossy().into_owned()))
+        .collect::<Result<Vec<_>>>()?;
+    entries.retain(|x| x != "mimetype" && !x.starts_with('.'));
+    entries.sort();
+    let mut args = vec!["-r9", &out_path];
+    args.extend(entries.iter().map(String::as_str));
+    run_zip(&epub_dir, &args)
+}
 
+/// Runs `zip` with `args` in `dir`.
+fn run_zip(dir: &str, args: &[&str]) -> Result<()> {
+    let output = Command::new("zip").current_dir(dir).args(args).output()?;
+    if !output.status.success() {
+        // zip reports some errors on its standard output
+        let message = [output.stderr, output.stdout]
+            .iter()
+            .map(|x| String::from_utf8_lossy(x).trim().to_string())
+            .find(|x| !x.is_empty())
+            .unwrap_or_default();
+        bail!("zip failed ({}): {message}", output.status);
+    }
     Ok(())
 }
 
@@ -387,5 +876,120 @@ pub fn get_metadata(file_path: &str) -> Result<Metadata, Box<dyn std::error::Err
             .mdata("page-progression-direction")
             .is_some_and(|x| x.value == "rtl"),
         blank: doc.mdata("blank").map(|x| x.value == "true"),
+        series: doc.mdata("belongs-to-collection").map(|x| x.value.clone()),
+        volume: None,
+        pages: None,
+        cover: None,
+        rendition: Rendition::default(),
+        accessibility: Accessibility::default(),
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::epub::rendition::{Orientation, PageRendition, Spread};
+
+    fn page(file_name: &str, generated: Option<GeneratedPage>) -> Image {
+        Image {
+            file_name: file_name.to_string(),
+            generated,
+            ..Image::default()
+        }
+    }
+
+    fn targets(landmarks: &[Landmark]) -> Vec<(&str, usize)> {
+        landmarks.iter().map(|x| (x.epub_type, x.part)).collect()
+    }
+
+    #[test]
+    fn spine_carries_spreads_and_overrides() {
+        let pages = [
+            page("cover", None),
+            page("1", None),
+            Image {
+                spread: Some(PageSpread::Center),
+                rendition: PageRendition {
+                    orientation: Some(Orientation::Landscape),
+                    ..PageRendition::default()
+                },
+                ..page("2", None)
+            },
+            page("after", Some(GeneratedPage::Text)),
+            page("3", None),
+        ];
+        let spine = spine_items(&pages, true, EpubVersion::Epub3, Vendor::Kindle);
+        let itemrefs = spine.lines().map(str::trim).collect::<Vec<_>>();
+        assert_eq!(
+            itemrefs,
+            [
+                r#"<itemref idref="part1" properties="page-spread-right"/>"#,
+                r#"<itemref idref="part2" properties="rendition:page-spread-center rendition:orientation-landscape"/>"#,
+                r#"<itemref idref="part3" properties="rendition:layout-reflowable"/>"#,
+                r#"<itemref idref="part4" properties="page-spread-right"/>"#,
+            ]
+        );
+
+        // Kobo leaves centering to `rendition:spread-none`, written once
+        let pages = [
+            page("cover", None),
+            Image {
+                spread: Some(PageSpread::Center),
+                rendition: PageRendition {
+                    spread: Some(Spread::None),
+                    ..PageRendition::default()
+                },
+                ..page("1", None)
+            },
+        ];
+        assert_eq!(
+            spine_items(&pages, true, EpubVersion::Epub3, Vendor::Kobo),
+            r#"<itemref idref="part1" properties="rendition:spread-none"/>"#
+        );
+        assert_eq!(
+            spine_items(&pages, true, EpubVersion::Epub2, Vendor::Kobo),
+            r#"<itemref idref="part1"/>"#
+        );
+    }
+
+    #[test]
+    fn landmarks_without_cover_page() {
+        let pages = [page("cover", None), page("1", None), page("2", None)];
+        assert_eq!(targets(&landmarks(&pages, false, 0)), [("bodymatter", 1)]);
+    }
+
+    #[test]
+    fn landmarks_around_generated_pages() {
+        let pages = [
+            page("cover", Some(GeneratedPage::Cover)),
+            page("title", Some(GeneratedPage::TitlePage)),
+            page("blank", None),
+            page("1", None),
+            page("2", None),
+            page("afterword", Some(GeneratedPage::Text)),
+        ];
+        assert_eq!(
+            targets(&landmarks(&pages, true, 0)),
+            [
+                ("cover", 0),
+                ("titlepage", 1),
+                ("bodymatter", 3),
+                ("backmatter", 5)
+            ]
+        );
+    }
+
+    #[test]
+    fn landmarks_skip_front_matter() {
+        let pages = [
+            page("cover", None),
+            page("title", Some(GeneratedPage::TitlePage)),
+            page("1", None),
+            page("2", None),
+        ];
+        assert_eq!(
+            targets(&landmarks(&pages, true, 2)),
+            [("cover", 0), ("titlepage", 1), ("bodymatter", 3)]
+        );
+    }
+}
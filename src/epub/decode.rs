@@ -0,0 +1,190 @@
+use std::{fs::read, io::Cursor, path::Path};
+
+use anyhow::{anyhow, bail, Result};
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader, RgbImage};
+use moxcms::{ColorProfile, DataColorSpace, Layout, TransformOptions};
+use zune_jpeg::{
+    zune_core::{colorspace::ColorSpace, options::DecoderOptions},
+    JpegDecoder,
+};
+
+/// Decodes an image the way it is meant to be displayed: turned upright as
+/// its EXIF orientation says, with the colors of an embedded ICC profile
+/// converted to sRGB.
+///
+/// # Errors
+///
+/// Returns an error if the file cannot be read or decoded, or its ICC
+/// profile cannot be applied.
+pub fn decode_image(path: &Path) -> Result<DynamicImage> {
+    let reader = ImageReader::open(path)?.with_guessed_format()?;
+    let format = reader.format();
+    let mut decoder = reader.into_decoder()?;
+    let orientation = decoder.orientation()?;
+    // Profiles that cannot be parsed are ignored, as viewers do
+    let profile = decoder
+        .icc_profile()?
+        .and_then(|x| ColorProfile::new_from_slice(&x).ok());
+
+    let mut image = match profile {
+        // The JPEG decoder converts CMYK to RGB on its own, without the
+        // profile, so such images are decoded separately
+        Some(profile)
+            if profile.color_space == DataColorSpace::Cmyk && format == Some(ImageFormat::Jpeg) =>
+        {
+            decode_cmyk_jpeg(&read(path)?, &profile)?
+        }
+        Some(profile) if profile.color_space == DataColorSpace::Rgb => {
+            to_srgb(DynamicImage::from_decoder(decoder)?, &profile)?
+        }
+        _ => DynamicImage::from_decoder(decoder)?,
+    };
+    image.apply_orientation(orientation);
+    Ok(image)
+}
+
+/// Converts RGB pixels from `profile` to sRGB, keeping the alpha channel if any.
+fn to_srgb(image: DynamicImage, profile: &ColorProfile) -> Result<DynamicImage> {
+    let srgb = ColorProfile::new_srgb();
+    if image.color().has_alpha() {
+        let src = image.into_rgba8();
+        let mut dst = src.clone();
+        profile
+            .create_transform_8bit(
+                Layout::Rgba,
+                &srgb,
+                Layout::Rgba,
+                TransformOptions::default(),
+            )?
+            .transform(&src, &mut dst)?;
+        Ok(DynamicImage::ImageRgba8(dst))
+    } else {
+        let src = image.into_rgb8();
+        let mut dst = src.clone();
+        profile
+            .create_transform_8bit(Layout::Rgb, &srgb, Layout::Rgb, TransformOptions::default())?
+            .transform(&src, &mut dst)?;
+        Ok(DynamicImage::ImageRgb8(dst))
+    }
+}
+
+/// Decodes a CMYK or YCCK JPEG to sRGB through its CMYK profile.
+///
+/// The samples are stored inverted, as Adobe applications write them.
+fn decode_cmyk_jpeg(bytes: &[u8], profile: &ColorProfile) -> Result<DynamicImage> {
+    let mut decoder = JpegDecoder::new(Cursor::new(bytes));
+    decoder.decode_headers()?;
+    let input = decoder
+        .input_colorspace()
+        .ok_or_else(|| anyhow!("missing JPEG colorspace"))?;
+    let info = decoder
+        .info()
+        .ok_or_else(|| anyhow!("missing JPEG header"))?;
+    if !matches!(input, ColorSpace::CMYK | ColorSpace::YCCK) {
+        bail!("JPEG with a CMYK profile has {input:?} samples");
+    }
+    // Take the samples as they are and do the conversion here
+    decoder.set_options(DecoderOptions::default().jpeg_set_out_colorspace(input));
+    let mut cmyk = decoder.decode()?;
+
+    for pixel in cmyk.chunks_exact_mut(4) {
+        if input == ColorSpace::YCCK {
+            // The YCC part decodes to the inverted CMY values, which are
+            // inverted once more by the Adobe convention
+            let [y, cb, cr] = [pixel[0], pixel[1], pixel[2]].map(f32::from);
+            let (cb, cr) = (cb - 128.0, cr - 128.0);
+            pixel[0] = clamp_u8(y + 1.402 * cr);
+            pixel[1] = clamp_u8(y - 0.344_136 * cb - 0.714_136 * cr);
+            pixel[2] = clamp_u8(y + 1.772 * cb);
+        } else {
+            for x in &mut pixel[..3] {
+                *x = 255 - *x;
+            }
+        }
+        pixel[3] = 255 - pixel[3];
+    }
+
+    let width = u32::from(info.width);
+    let height = u32::from(info.height);
+    let mut rgb = vec![0; cmyk.len() / 4 * 3];
+    // CMYK samples are laid out like RGBA ones
+    profile
+        .create_transform_8bit(
+            Layout::Rgba,
+            &ColorProfile::new_srgb(),
+            Layout::Rgb,
+            TransformOptions::default(),
+        )?
+        .transform(&cmyk, &mut rgb)?;
+    let image = RgbImage::from_raw(width, height, rgb)
+        .ok_or_else(|| anyhow!("unexpected size of decoded JPEG"))?;
+    Ok(DynamicImage::ImageRgb8(image))
+}
+
+#[expect(
+    clippy::cast_possible_truncation,
+    clippy::cast_sign_loss,
+    reason = "the value is clamped to the range of u8"
+)]
+fn clamp_u8(x: f32) -> u8 {
+    x.round().clamp(0.0, 255.0) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_fixture(name: &str) -> Result<RgbImage> {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("fixtures")
+            .join(name);
+        Ok(decode_image(&path)?.to_rgb8())
+    }
+
+    /// Asserts that the pixel at `x`, `y` is `expected`, give or take the
+    /// rounding of lossy compression and color conversion.
+    fn assert_pixel(image: &RgbImage, (x, y): (u32, u32), expected: [u8; 3]) {
+        let actual = image.get_pixel(x, y).0;
+        assert!(
+            actual.iter().zip(expected).all(|(a, e)| a.abs_diff(e) <= 3),
+            "pixel at {x}, {y} is {actual:?}, expected {expected:?}"
+        );
+    }
+
+    #[test]
+    fn exif_orientation_is_applied() -> Result<()> {
+        let image = decode_fixture("1-exif-orientation.jpg")?;
+        assert_eq!(image.dimensions(), (20, 40));
+        assert_pixel(&image, (10, 10), [255, 0, 0]);
+        assert_pixel(&image, (10, 30), [0, 0, 255]);
+        Ok(())
+    }
+
+    #[test]
+    fn rgb_profile_is_converted_to_srgb() -> Result<()> {
+        let image = decode_fixture("2-icc-linear-rgb.png")?;
+        assert_eq!(image.dimensions(), (40, 20));
+        // Gray 128 in linear RGB
+        assert_pixel(&image, (20, 10), [188, 188, 188]);
+        Ok(())
+    }
+
+    #[test]
+    fn cmyk_without_profile_is_converted_naively() -> Result<()> {
+        let image = decode_fixture("3-cmyk.jpg")?;
+        assert_eq!(image.dimensions(), (40, 20));
+        assert_pixel(&image, (10, 10), [0, 255, 255]);
+        assert_pixel(&image, (30, 10), [128, 128, 128]);
+        Ok(())
+    }
+
+    #[test]
+    fn cmyk_profile_is_converted_to_srgb() -> Result<()> {
+        let image = decode_fixture("4-cmyk-icc.jpg")?;
+        assert_eq!(image.dimensions(), (40, 20));
+        assert_pixel(&image, (10, 10), [0, 255, 255]);
+        // The profile takes 50% black to half the light, not half of 255
+        assert_pixel(&image, (30, 10), [188, 188, 188]);
+        Ok(())
+    }
+}
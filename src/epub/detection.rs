@@ -0,0 +1,389 @@
+use std::{ops::Range, path::Path};
+
+use anyhow::Result;
+use image::{GrayImage, Rgb, RgbImage};
+
+use super::{
+    decode::decode_image,
+    panels::{listed_panels, Panel},
+};
+
+/// Difference in brightness from the gutter up to which a pixel still
+/// belongs to the gutter.
+const GUTTER_TOLERANCE: u8 = 24;
+
+/// Share of a line, in thousandths, that may differ from the gutter, such as
+/// dust on a scan, while the line still counts as gutter.
+const GUTTER_NOISE: u32 = 5;
+
+/// A rectangle of an image in pixels, with end-exclusive ranges.
+#[derive(Debug, Clone)]
+struct Rect {
+    x: Range<u32>,
+    y: Range<u32>,
+}
+
+/// Pixels of an image told apart from the gutters around panels.
+struct Page {
+    image: GrayImage,
+    gutter: u8,
+    min_gutter: u32,
+}
+
+impl Page {
+    fn is_content(&self, x: u32, y: u32) -> bool {
+        self.image.get_pixel(x, y).0[0].abs_diff(self.gutter) > GUTTER_TOLERANCE
+    }
+
+    /// Whether the row `y` is gutter across the columns `x`.
+    fn is_gutter_row(&self, x: &Range<u32>, y: u32) -> bool {
+        let content = x.clone().filter(|&x| self.is_content(x, y)).count();
+        u32::try_from(content).unwrap_or(u32::MAX) * 1000 <= (x.end - x.start) * GUTTER_NOISE
+    }
+
+    /// Whether the column `x` is gutter across the rows `y`.
+    fn is_gutter_column(&self, x: u32, y: &Range<u32>) -> bool {
+        let content = y.clone().filter(|&y| self.is_content(x, y)).count();
+        u32::try_from(content).unwrap_or(u32::MAX) * 1000 <= (y.end - y.start) * GUTTER_NOISE
+    }
+
+    /// Shrinks `rect` to the content in it, if there is any.
+    fn trim(&self, rect: &Rect) -> Option<Rect> {
+        let rows = rect
+            .y
+            .clone()
+            .filter(|&y| !self.is_gutter_row(&rect.x, y))
+            .collect::<Vec<_>>();
+        let columns = rect
+            .x
+            .clone()
+            .filter(|&x| !self.is_gutter_column(x, &rect.y))
+            .collect::<Vec<_>>();
+        Some(Rect {
+            x: *columns.first()?..*columns.last()? + 1,
+            y: *rows.first()?..*rows.last()? + 1,
+        })
+    }
+
+    /// Cuts `rect` at its gutters, rows of panels first, then the panels of
+    /// each row, and so on until no gutter is left, collecting the panels in
+    /// reading order.
+    fn cut(&self, rect: &Rect, is_rtl: bool, panels: &mut Vec<Rect>) {
+        let Some(rect) = self.trim(rect) else {
+            return;
+        };
+        let rows = runs(rect.y.clone(), self.min_gutter, |y| {
+            self.is_gutter_row(&rect.x, y)
+        });
+        if rows.len() > 1 {
+            for y in rows {
+                self.cut(
+                    &Rect {
+                        x: rect.x.clone(),
+                        y,
+                    },
+                    is_rtl,
+                    panels,
+                );
+            }
+            return;
+        }
+        let mut columns = runs(rect.x.clone(), self.min_gutter, |x| {
+            self.is_gutter_column(x, &rect.y)
+        });
+        if columns.len() > 1 {
+            if is_rtl {
+                columns.reverse();
+            }
+            for x in columns {
+                self.cut(
+                    &Rect {
+                        x,
+                        y: rect.y.clone(),
+                    },
+                    is_rtl,
+                    panels,
+                );
+            }
+            return;
+        }
+        panels.push(rect);
+    }
+}
+
+/// Finds the panels of the image at `path` by the gutters between them, in
+/// reading order.
+///
+/// The page is cut along gutters that run all the way across it, then each
+/// part along the gutters across that part, and so on. Rows are read from
+/// top to bottom, and the panels of a row from right to left if `is_rtl` is
+/// set. The gutters take the color of the edges of the page. A page in which
+/// no gutter is found has no panels.
+///
+/// # Errors
+///
+/// Returns an error if the image cannot be decoded.
+pub fn detect_panels(path: &Path, is_rtl: bool) -> Result<Vec<Panel>> {
+    let image = decode_image(path)?.to_luma8();
+    let (width, height) = image.dimensions();
+    let page = Page {
+        gutter: edge_luma(&image),
+        min_gutter: (width.min(height) / 200).max(2),
+        image,
+    };
+
+    let mut rects = Vec::new();
+    page.cut(
+        &Rect {
+            x: 0..width,
+            y: 0..height,
+        },
+        is_rtl,
+        &mut rects,
+    );
+
+    // Specks between gutters are not panels
+    let panels = rects
+        .into_iter()
+        .map(|x| {
+            [
+                x.x.start,
+                x.y.start,
+                x.x.end - x.x.start,
+                x.y.end - x.y.start,
+            ]
+        })
+        .filter(|[_, _, w, h]| {
+            u64::from(*w) * u64::from(*h) * 100 >= u64::from(width) * u64::from(height)
+        })
+        .collect::<Vec<_>>();
+    if panels.len() < 2 {
+        return Ok(Vec::new());
+    }
+    listed_panels(&panels, width, height)
+}
+
+/// Average brightness of the outermost pixels, taken as the color of the
+/// gutters.
+fn edge_luma(image: &GrayImage) -> u8 {
+    let (width, height) = image.dimensions();
+    let (sum, count) = image
+        .enumerate_pixels()
+        .filter(|(x, y, _)| *x == 0 || *y == 0 || x + 1 == width || y + 1 == height)
+        .fold((0u64, 0u64), |(sum, count), (_, _, p)| {
+            (sum + u64::from(p.0[0]), count + 1)
+        });
+    u8::try_from(sum / count.max(1)).unwrap_or(u8::MAX)
+}
+
+/// Splits `range` into the runs of positions that are not gutters, where a
+/// gutter is at least `min_gutter` consecutive positions for which
+/// `is_gutter` holds.
+fn runs(range: Range<u32>, min_gutter: u32, is_gutter: impl Fn(u32) -> bool) -> Vec<Range<u32>> {
+    let mut runs = Vec::new();
+    let mut start = None;
+    let mut gutter = 0;
+    let end = range.end;
+    for i in range {
+        if is_gutter(i) {
+            gutter += 1;
+            if let Some(s) = start.filter(|_| gutter == min_gutter) {
+                runs.push(s..i + 1 - min_gutter);
+                start = None;
+            }
+        } else {
+            gutter = 0;
+            start.get_or_insert(i);
+        }
+    }
+    if let Some(s) = start {
+        runs.push(s..end);
+    }
+    runs
+}
+
+/// Colors the panels are outlined in, in turn.
+const OVERLAY_COLORS: [[u8; 3]; 6] = [
+    [230, 25, 75],
+    [60, 180, 75],
+    [0, 130, 200],
+    [245, 130, 48],
+    [145, 30, 180],
+    [0, 170, 170],
+];
+
+/// Digits of 3 x 5 pixels, one row per byte with the leftmost pixel in the
+/// highest of three bits.
+const DIGITS: [[u8; 5]; 10] = [
+    [0b111, 0b101, 0b101, 0b101, 0b111],
+    [0b010, 0b110, 0b010, 0b010, 0b111],
+    [0b111, 0b001, 0b111, 0b100, 0b111],
+    [0b111, 0b001, 0b111, 0b001, 0b111],
+    [0b101, 0b101, 0b111, 0b001, 0b001],
+    [0b111, 0b100, 0b111, 0b001, 0b111],
+    [0b111, 0b100, 0b111, 0b101, 0b111],
+    [0b111, 0b001, 0b001, 0b001, 0b001],
+    [0b111, 0b101, 0b111, 0b101, 0b111],
+    [0b111, 0b101, 0b111, 0b001, 0b111],
+];
+
+/// Writes the image at `path` to `out` with `panels` outlined and numbered
+/// in reading order, to check what [`detect_panels`] found.
+///
+/// # Errors
+///
+/// Returns an error if the image cannot be decoded or the output cannot be
+/// written.
+pub fn write_panel_overlay(path: &Path, panels: &[Panel], out: &Path) -> Result<()> {
+    let mut image = decode_image(path)?.to_rgb8();
+    let (width, height) = image.dimensions();
+    let line = (width.min(height) / 300).max(2);
+    let scale = (width.min(height) / 100).max(2);
+    for (i, panel) in panels.iter().enumerate() {
+        let color = Rgb(OVERLAY_COLORS[i % OVERLAY_COLORS.len()]);
+        let [x, y, w, h] = panel.pixels(width, height);
+        let (right, bottom) = ((x + w).min(width), (y + h).min(height));
+        fill(&mut image, x..right, y..(y + line).min(bottom), color);
+        fill(
+            &mut image,
+            x..right,
+            bottom.saturating_sub(line)..bottom,
+            color,
+        );
+        fill(&mut image, x..(x + line).min(right), y..bottom, color);
+        fill(
+            &mut image,
+            right.saturating_sub(line)..right,
+            y..bottom,
+            color,
+        );
+
+        // The number sits on a box of the outline's color in the corner
+        let number = (i + 1).to_string();
+        let digits = u32::try_from(number.len()).unwrap_or(0);
+        let (left, top) = (x + line, y + line);
+        fill(
+            &mut image,
+            left..left + scale * (4 * digits + 1),
+            top..top + scale * 7,
+            color,
+        );
+        for (j, digit) in number.bytes().enumerate() {
+            let glyph = DIGITS[usize::from(digit - b'0')];
+            let glyph_left = left + scale * (1 + 4 * u32::try_from(j).unwrap_or(0));
+            for (row, bits) in (0..).zip(glyph) {
+                for column in 0..3 {
+                    if bits & (0b100 >> column) != 0 {
+                        let px = glyph_left + column * scale;
+                        let py = top + scale * (1 + row);
+                        fill(&mut image, px..px + scale, py..py + scale, Rgb([255; 3]));
+                    }
+                }
+            }
+        }
+    }
+    image.save(out)?;
+    Ok(())
+}
+
+/// Fills the part of `x` by `y` that lies within the image with `color`.
+fn fill(image: &mut RgbImage, x: Range<u32>, y: Range<u32>, color: Rgb<u8>) {
+    let (width, height) = image.dimensions();
+    for py in y.start.min(height)..y.end.min(height) {
+        for px in x.start.min(width)..x.end.min(width) {
+            image.put_pixel(px, py, color);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::epub::testing::TestDir;
+
+    const WIDTH: u32 = 200;
+    const HEIGHT: u32 = 300;
+
+    /// Panels of a 2 x 2 grid, in reading order from left to right
+    const GRID: [[u32; 4]; 4] = [
+        [10, 10, 85, 135],
+        [105, 10, 85, 135],
+        [10, 155, 85, 135],
+        [105, 155, 85, 135],
+    ];
+
+    /// Saves a white page with the black panels at `rects` as `name`.
+    fn page(dir: &TestDir, name: &str, rects: &[[u32; 4]]) -> Result<PathBuf> {
+        let mut image = RgbImage::from_pixel(WIDTH, HEIGHT, Rgb([255; 3]));
+        for &[x, y, w, h] in rects {
+            fill(&mut image, x..x + w, y..y + h, Rgb([0; 3]));
+        }
+        let path = dir.path(name);
+        image.save(&path)?;
+        Ok(path)
+    }
+
+    fn detected(path: &Path, is_rtl: bool) -> Result<Vec<[u32; 4]>> {
+        Ok(detect_panels(path, is_rtl)?
+            .into_iter()
+            .map(|x| x.pixels(WIDTH, HEIGHT))
+            .collect())
+    }
+
+    #[test]
+    fn grid_is_read_by_rows() -> Result<()> {
+        let dir = TestDir::new(&[])?;
+        let path = page(&dir, "grid.png", &GRID)?;
+        assert_eq!(detected(&path, false)?, GRID);
+        Ok(())
+    }
+
+    #[test]
+    fn rows_are_read_right_to_left() -> Result<()> {
+        let dir = TestDir::new(&[])?;
+        let path = page(&dir, "grid.png", &GRID)?;
+        assert_eq!(detected(&path, true)?, [GRID[1], GRID[0], GRID[3], GRID[2]]);
+        Ok(())
+    }
+
+    #[test]
+    fn columns_are_cut_within_a_row() -> Result<()> {
+        let dir = TestDir::new(&[])?;
+        let top = [10, 10, 180, 90];
+        let (left_top, left_bottom) = ([10, 110, 85, 85], [10, 205, 85, 85]);
+        let right = [105, 110, 85, 180];
+        let path = page(&dir, "nested.png", &[top, left_top, left_bottom, right])?;
+        assert_eq!(detected(&path, false)?, [top, left_top, left_bottom, right]);
+        assert_eq!(detected(&path, true)?, [top, right, left_top, left_bottom]);
+        Ok(())
+    }
+
+    #[test]
+    fn page_without_gutters_has_no_panels() -> Result<()> {
+        let dir = TestDir::new(&[])?;
+        let mut state = 1_u32;
+        let image = RgbImage::from_fn(WIDTH, HEIGHT, |_, _| {
+            state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            Rgb([state.to_be_bytes()[0]; 3])
+        });
+        let path = dir.path("bleed.png");
+        image.save(&path)?;
+        assert!(detect_panels(&path, false)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn overlay_is_written() -> Result<()> {
+        let dir = TestDir::new(&[])?;
+        let path = page(&dir, "grid.png", &GRID)?;
+        let out = dir.path("overlay.png");
+        write_panel_overlay(&path, &detect_panels(&path, false)?, &out)?;
+        let overlay = image::open(&out)?.to_rgb8();
+        assert_eq!(overlay.dimensions(), (WIDTH, HEIGHT));
+        // The first panel is outlined in the first color
+        assert_eq!(overlay.get_pixel(10, 10).0, OVERLAY_COLORS[0]);
+        Ok(())
+    }
+}
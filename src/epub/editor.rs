@@ -0,0 +1,597 @@
+use std::{
+    fs::{read_to_string, remove_file, rename, File},
+    io::{BufReader, Read, Seek, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use regex::{Captures, Regex};
+use uuid::Uuid;
+use zip::{write::SimpleFileOptions, ZipArchive, ZipWriter};
+
+use super::{
+    accessibility::Accessibility,
+    compat::EpubVersion,
+    converter::{escape_xml, unescape_xml, Metadata},
+    generated::GeneratedPage,
+    images::{Image, PageOptions},
+    pages::PageSpread,
+    panels::{listed_panels, Panel},
+    rendition::{Layout, PageRendition, Rendition},
+    vendor::Vendor,
+};
+
+/// Metadata fields to rewrite in an existing EPUB.
+/// Fields left as `None` are kept as they are.
+#[derive(Debug, Default)]
+pub struct MetadataUpdate {
+    pub title: Option<String>,
+    pub creator: Option<String>,
+    pub publisher: Option<String>,
+    pub date: Option<String>,
+}
+
+/// Returns the path of the package document declared in `META-INF/container.xml`.
+fn rootfile_path<R: Read + Seek>(archive: &mut ZipArchive<R>) -> Result<String> {
+    let container = read_entry(archive, "META-INF/container.xml")?;
+    let re = Regex::new(r#"<rootfile\b[^>]*\bfull-path="([^"]+)""#)?;
+    re.captures(&container)
+        .and_then(|c| c.get(1))
+        .map(|m| m.as_str().to_string())
+        .ok_or_else(|| anyhow!("no rootfile in META-INF/container.xml"))
+}
+
+fn read_entry<R: Read + Seek>(archive: &mut ZipArchive<R>, name: &str) -> Result<String> {
+    let mut content = String::new();
+    archive
+        .by_name(name)
+        .map_err(|e| anyhow!("failed to read {name}: {e}"))?
+        .read_to_string(&mut content)?;
+    Ok(content)
+}
+
+/// Replaces the text of the first `<dc:{name}>` element, or appends a new one
+/// to the end of `<metadata>` if the package has none.
+fn replace_dc_element(opf: &str, name: &str, value: &str) -> Result<String> {
+    let value = escape_xml(value);
+    let re = Regex::new(&format!(r"(?s)(<dc:{name}\b[^>]*>).*?(</dc:{name}>)"))?;
+    if re.is_match(opf) {
+        return Ok(re
+            .replacen(opf, 1, |c: &Captures| format!("{}{value}{}", &c[1], &c[2]))
+            .into_owned());
+    }
+    let end = opf
+        .find("</metadata>")
+        .ok_or_else(|| anyhow!("no <metadata> element in package document"))?;
+    Ok(format!(
+        "{}    <dc:{name}>{value}</dc:{name}>\n    {}",
+        &opf[..end],
+        &opf[end..]
+    ))
+}
+
+fn update_package(opf: &str, update: &MetadataUpdate) -> Result<String> {
+    let mut opf = opf.to_string();
+    for (name, value) in [
+        ("title", &update.title),
+        ("creator", &update.creator),
+        ("publisher", &update.publisher),
+        ("date", &update.date),
+    ] {
+        if let Some(value) = value {
+            opf = replace_dc_element(&opf, name, value)?;
+        }
+    }
+
+    // Keep dcterms:modified in step with the change
+    let modified = Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string();
+    let re = Regex::new(r#"(?s)(<meta\b[^>]*\bproperty="dcterms:modified"[^>]*>).*?(</meta>)"#)?;
+    Ok(re
+        .replacen(&opf, 1, |c: &Captures| {
+            format!("{}{modified}{}", &c[1], &c[2])
+        })
+        .into_owned())
+}
+
+/// Rewrites the metadata of an existing EPUB in place.
+///
+/// Only the package document (`content.opf`) is regenerated; every other
+/// entry, including the leading uncompressed `mimetype`, is copied
+/// byte-for-byte in its original order.
+///
+/// # Errors
+///
+/// Returns an error if:
+/// - The file is not a readable ZIP archive.
+/// - The package document cannot be located or has no `<metadata>` element.
+/// - Writing or replacing the output file fails.
+pub fn set_metadata(file_path: &str, update: &MetadataUpdate) -> Result<()> {
+    let mut archive = ZipArchive::new(BufReader::new(File::open(file_path)?))?;
+    let opf_path = rootfile_path(&mut archive)?;
+    let opf = update_package(&read_entry(&mut archive, &opf_path)?, update)?;
+
+    let tmp_path = format!("{file_path}.{}.tmp", Uuid::new_v4());
+    let result = write_with_package(&mut archive, &opf_path, &opf, &tmp_path)
+        .and_then(|()| Ok(rename(&tmp_path, file_path)?));
+    if result.is_err() {
+        let _ = remove_file(&tmp_path);
+    }
+    result
+}
+
+/// Writes a copy of `archive` to `path` with the package document at
+/// `opf_path` replaced by `opf`.
+fn write_with_package<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    opf_path: &str,
+    opf: &str,
+    path: &str,
+) -> Result<()> {
+    let mut writer = ZipWriter::new(File::create(path)?);
+    for i in 0..archive.len() {
+        let entry = archive.by_index_raw(i)?;
+        if entry.name() == opf_path {
+            let mut options = SimpleFileOptions::default().compression_method(entry.compression());
+            if let Some(time) = entry.last_modified() {
+                options = options.last_modified_time(time);
+            }
+            let name = entry.name().to_string();
+            drop(entry);
+            writer.start_file(name, options)?;
+            writer.write_all(opf.as_bytes())?;
+        } else {
+            writer.raw_copy_file(entry)?;
+        }
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+/// An EPUB previously generated by `img2epub`, extracted to a working directory.
+pub struct GeneratedBook {
+    pub identifier: String,
+    pub language: String,
+    pub metadata: Metadata,
+    pub width: u32,
+    pub height: u32,
+    /// Pages in reading order; the first one is the cover.
+    pub pages: Vec<Image>,
+    /// Whether the cover is shown as a page of its own
+    pub cover_page: bool,
+    pub version: EpubVersion,
+    pub vendor: Vendor,
+    /// Background, padding and native size the pages were processed with
+    pub page_options: PageOptions,
+}
+
+fn dc_element(opf: &str, name: &str) -> Result<Option<String>> {
+    let re = Regex::new(&format!(r"(?s)<dc:{name}\b[^>]*>(.*?)</dc:{name}>"))?;
+    Ok(re.captures(opf).map(|c| unescape_xml(c[1].trim())))
+}
+
+fn attribute(tag: &str, name: &str) -> Result<Option<String>> {
+    let re = Regex::new(&format!(r#"\s{name}="([^"]*)""#))?;
+    Ok(re.captures(tag).map(|c| unescape_xml(&c[1])))
+}
+
+/// Generated covers are the only SVG images `img2epub` writes; they are drawn
+/// again from the metadata when the book is rebuilt.
+fn generated_cover(path: &Path) -> Option<GeneratedPage> {
+    (path.extension().is_some_and(|x| x == "svg")).then_some(GeneratedPage::Cover)
+}
+
+/// Returns the href of the manifest item with the given id.
+fn item_href(items: &[(Option<String>, Option<String>)], id: &str) -> Option<String> {
+    items
+        .iter()
+        .find(|(x, _)| x.as_deref() == Some(id))
+        .and_then(|(_, href)| href.clone())
+}
+
+/// Reads back the labels in the page list of a navigation document, with the
+/// number of the part file each points at.
+fn page_labels(nav: &str) -> Result<Vec<(usize, String)>> {
+    let Some(list) =
+        Regex::new(r#"(?s)<nav epub:type="page-list"[^>]*>(.*?)</nav>"#)?.captures(nav)
+    else {
+        return Ok(Vec::new());
+    };
+    let re = Regex::new(r#"<a href="part(\d+)\.xhtml">(.*?)</a>"#)?;
+    Ok(re
+        .captures_iter(&list[1])
+        .filter_map(|c| Some((c[1].parse().ok()?, unescape_xml(&c[2]))))
+        .collect())
+}
+
+/// Same as [`page_labels`], for the page list of an NCX table of contents.
+fn ncx_page_labels(ncx: &str) -> Result<Vec<(usize, String)>> {
+    let re = Regex::new(
+        r#"(?s)<pageTarget[^>]*>\s*<navLabel><text>(.*?)</text></navLabel>\s*<content src="part(\d+)\.xhtml"/>"#,
+    )?;
+    Ok(re
+        .captures_iter(ncx)
+        .filter_map(|c| Some((c[2].parse().ok()?, unescape_xml(&c[1]))))
+        .collect())
+}
+
+/// Reads back the page labels from the navigation document, or from the NCX
+/// table of contents of an EPUB 2 book.
+fn read_page_labels(
+    opf_dir: &Path,
+    items: &[(Option<String>, Option<String>)],
+) -> Result<Vec<(usize, String)>> {
+    match (item_href(items, "nav"), item_href(items, "ncx")) {
+        (Some(nav), _) => page_labels(&read_to_string(opf_dir.join(nav))?),
+        (None, Some(ncx)) => ncx_page_labels(&read_to_string(opf_dir.join(ncx))?),
+        (None, None) => Ok(Vec::new()),
+    }
+}
+
+/// Reads back the orientation and spreads of the book, or of the Kindle
+/// orientation lock of an EPUB 2 book.
+fn read_rendition(opf: &str) -> Result<Rendition> {
+    let property = |name: &str| -> Result<Option<String>> {
+        Ok(Regex::new(&format!(
+            r#"<meta property="rendition:{name}">([a-z-]+)</meta>"#
+        ))?
+        .captures(opf)
+        .map(|c| c[1].to_string()))
+    };
+    let orientation = property("orientation")?.or(Regex::new(
+        r#"<meta name="orientation-lock" content="([a-z]+)"/>"#,
+    )?
+    .captures(opf)
+    .map(|c| c[1].to_string()));
+    Ok(Rendition {
+        orientation: orientation
+            .map(|x| x.parse())
+            .transpose()?
+            .unwrap_or_default(),
+        spread: property("spread")?
+            .map(|x| x.parse())
+            .transpose()?
+            .unwrap_or_default(),
+    })
+}
+
+/// Reads back the options the pages were processed with. Books written
+/// before they were recorded get the defaults.
+fn read_page_options(opf: &str) -> Result<PageOptions> {
+    let meta = |name: &str| -> Result<Option<String>> {
+        Ok(Regex::new(&format!(
+            r#"<meta name="img2epub:{name}" content="([^"]*)"/>"#
+        ))?
+        .captures(opf)
+        .map(|c| c[1].to_string()))
+    };
+    let mut options = PageOptions::default();
+    if let Some(background) = meta("background")? {
+        options.background = background.parse()?;
+    }
+    if let Some(padding) = meta("padding")? {
+        options.padding = padding.parse()?;
+    }
+    options.native_size = meta("native-size")?.is_some_and(|x| x == "true");
+    options.quality = meta("quality")?.map(|x| x.parse()).transpose()?;
+    Ok(options)
+}
+
+/// Reads back the accessibility metadata of the package, as written for
+/// EPUB 3 or EPUB 2, leaving out what was filled in from `pages`.
+fn read_accessibility(opf: &str, pages: &[Image]) -> Result<Accessibility> {
+    let re = Regex::new(
+        r#"<meta (?:property="schema:(\w+)">([^<]*)</meta>|name="schema:(\w+)" content="([^"]*)"/>)"#,
+    )?;
+    let mut accessibility = Accessibility::default();
+    for c in re.captures_iter(opf) {
+        let (name, value) = match (c.get(1), c.get(2), c.get(3), c.get(4)) {
+            (Some(name), Some(value), _, _) | (_, _, Some(name), Some(value)) => {
+                (name.as_str(), unescape_xml(value.as_str()))
+            }
+            _ => continue,
+        };
+        match name {
+            "accessMode" => accessibility.access_modes.push(value),
+            "accessibilityFeature" => accessibility.features.push(value),
+            "accessibilityHazard" => accessibility.hazards.push(value),
+            "accessibilitySummary" => accessibility.summary = Some(value),
+            _ => {}
+        }
+    }
+    Ok(accessibility.without_defaults(pages))
+}
+
+/// Reads back the cover image of a book whose cover is not shown as a page.
+/// It is as large as the canvas, or smaller with native size.
+fn read_cover_image(
+    opf_dir: &Path,
+    items: &[(Option<String>, Option<String>)],
+    canvas: (u32, u32),
+) -> Result<Image> {
+    let href = item_href(items, "cover").ok_or_else(|| anyhow!("no cover image in manifest"))?;
+    let path = opf_dir.join(&href);
+    let generated = generated_cover(&path);
+    let (width, height) = match generated {
+        Some(_) => canvas,
+        None => image::image_dimensions(&path)?,
+    };
+    Ok(Image {
+        generated,
+        path,
+        file_name: "cover".to_string(),
+        width,
+        height,
+        encoded: true,
+        ..Image::default()
+    })
+}
+
+/// Returns the size of the viewport of a part file, if it has one.
+fn viewport(part: &str) -> Result<Option<(u32, u32)>> {
+    Regex::new(r#"<meta name="viewport" content="width=(\d+), height=(\d+)"/>"#)?
+        .captures(part)
+        .map(|c| Ok((c[1].parse()?, c[2].parse()?)))
+        .transpose()
+}
+
+/// Reads back the page shown by the part file at `href`, at the size of its
+/// viewport. `canvas` is the size of the book, which text pages take.
+fn read_part(opf_dir: &Path, href: &str, img_re: &Regex, canvas: (u32, u32)) -> Result<Image> {
+    let part = read_to_string(opf_dir.join(href))?;
+    // Only fixed-layout pages have a viewport; text pages are kept as the
+    // XHTML they were written as
+    let Some((width, height)) = viewport(&part)? else {
+        return Ok(Image {
+            path: opf_dir.join(href),
+            width: canvas.0,
+            height: canvas.1,
+            generated: Some(GeneratedPage::Text),
+            ..Image::default()
+        });
+    };
+    if part.contains(r#"class="titlepage""#) {
+        return Ok(Image {
+            file_name: "title".to_string(),
+            width,
+            height,
+            generated: Some(GeneratedPage::TitlePage),
+            ..Image::default()
+        });
+    }
+    let src = img_re
+        .captures(&part)
+        .map(|c| unescape_xml(&c[1]))
+        .ok_or_else(|| anyhow!("{href} does not contain an image"))?;
+    let path = opf_dir.join(&src);
+    let file_name = path
+        .file_stem()
+        .and_then(|x| x.to_str())
+        .ok_or_else(|| anyhow!("invalid image path: {src}"))?
+        .to_string();
+    // What stands in for a missing description is told apart once the
+    // labels are known
+    let alt = Regex::new(r#"<img\b[^>]*\salt="([^"]*)""#)?
+        .captures(&part)
+        .map(|c| unescape_xml(&c[1]))
+        .filter(|x| href != "part0.xhtml" || x != "cover");
+    Ok(Image {
+        generated: generated_cover(&path),
+        path,
+        file_name,
+        width,
+        height,
+        panels: read_panels(&part)?,
+        alt,
+        encoded: true,
+        ..Image::default()
+    })
+}
+
+/// Reads back the panel regions of a part file, relative to its viewport.
+fn read_panels(part: &str) -> Result<Vec<Panel>> {
+    let Some((width, height)) = viewport(part)? else {
+        return Ok(Vec::new());
+    };
+    let re = Regex::new(
+        r#"<div id="panel-\d+" epub:type="panel" style="height: (\d+)px; left: (\d+)px; position: absolute; top: (\d+)px; width: (\d+)px">"#,
+    )?;
+    let rects = re
+        .captures_iter(part)
+        .map(|c| Ok([c[2].parse()?, c[3].parse()?, c[4].parse()?, c[1].parse()?]))
+        .collect::<Result<Vec<_>>>()?;
+    listed_panels(&rects, width, height)
+}
+
+/// Gives the pages back the spreads they were forced onto and the rendition
+/// properties they override, from the `properties` of their spine entries.
+/// Sides that only follow from the alternation of the spreads are left to
+/// be worked out again, the way the spine is written.
+fn restore_spine_properties(
+    pages: &mut [Image],
+    spine_properties: &[Option<String>],
+    is_rtl: bool,
+    vendor: Vendor,
+) -> Result<()> {
+    let first = if is_rtl {
+        PageSpread::Right
+    } else {
+        PageSpread::Left
+    };
+    let mut next = first;
+    for (page, properties) in pages.iter_mut().zip(spine_properties).skip(1) {
+        let mut spread = None;
+        let mut rendition = PageRendition::default();
+        for property in properties.iter().flat_map(|x| x.split_whitespace()) {
+            match property {
+                "page-spread-left" => spread = Some(PageSpread::Left),
+                "page-spread-right" => spread = Some(PageSpread::Right),
+                "rendition:page-spread-center" => spread = Some(PageSpread::Center),
+                // Kobo shows a centered page on its own instead
+                "rendition:spread-none" if vendor == Vendor::Kobo => {
+                    spread = Some(PageSpread::Center);
+                }
+                "rendition:layout-reflowable" => rendition.layout = Some(Layout::Reflowable),
+                "rendition:layout-pre-paginated" => {
+                    rendition.layout = Some(Layout::PrePaginated);
+                }
+                _ => {
+                    if let Some(x) = property.strip_prefix("rendition:orientation-") {
+                        rendition.orientation = Some(x.parse()?);
+                    } else if let Some(x) = property.strip_prefix("rendition:spread-") {
+                        rendition.spread = Some(x.parse()?);
+                    }
+                }
+            }
+        }
+        // Text pages reflow without saying so
+        if page.generated == Some(GeneratedPage::Text)
+            && rendition.layout == Some(Layout::Reflowable)
+        {
+            rendition.layout = None;
+        }
+        page.rendition = rendition;
+
+        if rendition.is_reflowable(page.generated) {
+            next = first;
+            continue;
+        }
+        let Some(spread) = spread else {
+            continue;
+        };
+        page.spread = (spread != next).then_some(spread);
+        next = match spread {
+            PageSpread::Left => PageSpread::Right,
+            PageSpread::Right => PageSpread::Left,
+            PageSpread::Center => first,
+        };
+    }
+    Ok(())
+}
+
+/// Gives the pages back their printed page numbers, and drops the
+/// alternative text written in place of a missing description.
+fn restore_labels(pages: &mut [Image], labels: Vec<(usize, String)>) {
+    for (n, label) in labels {
+        if let Some(page) = pages.get_mut(n) {
+            page.label = Some(label);
+        }
+    }
+    for page in pages {
+        let description = page.alt.take();
+        page.alt = description.filter(|x| *x != page.alt_text());
+    }
+}
+
+/// Extracts an EPUB generated by `img2epub` into `dir` and reads back its pages.
+///
+/// # Errors
+///
+/// Returns an error if:
+/// - The file cannot be extracted.
+/// - The package document is missing or was not generated by `img2epub`.
+/// - A fixed-layout page in the spine does not reference an image and is not
+///   a title page.
+pub fn read_generated_book(file_path: &str, dir: &str) -> Result<GeneratedBook> {
+    let mut archive = ZipArchive::new(BufReader::new(File::open(file_path)?))?;
+    let opf_path = rootfile_path(&mut archive)?;
+    archive.extract(dir)?;
+
+    let opf_file = PathBuf::from(dir).join(&opf_path);
+    let opf_dir = opf_file
+        .parent()
+        .ok_or_else(|| anyhow!("invalid package document path: {opf_path}"))?
+        .to_path_buf();
+    let opf = read_to_string(&opf_file)?;
+
+    let resolution = Regex::new(r#"<meta name="original-resolution" content="(\d+)x(\d+)"/>"#)?
+        .captures(&opf)
+        .ok_or_else(|| anyhow!("{file_path} was not generated by img2epub"))?;
+    let width = resolution[1].parse::<u32>()?;
+    let height = resolution[2].parse::<u32>()?;
+
+    let item_re = Regex::new(r"<item\b[^>]*>")?;
+    let items = item_re
+        .find_iter(&opf)
+        .map(|m| Ok((attribute(m.as_str(), "id")?, attribute(m.as_str(), "href")?)))
+        .collect::<Result<Vec<_>>>()?;
+    let img_re = Regex::new(r#"<img\b[^>]*\ssrc="([^"]+)""#)?;
+    let itemrefs = Regex::new(r"<itemref\b[^>]*>")?
+        .find_iter(&opf)
+        .map(|m| {
+            let idref = attribute(m.as_str(), "idref")?
+                .ok_or_else(|| anyhow!("spine entry without idref: {}", m.as_str()))?;
+            Ok((idref, attribute(m.as_str(), "properties")?))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let cover_page = itemrefs.iter().any(|(x, _)| x == "part0");
+    let version = if opf.contains(r#"version="2.0""#) {
+        EpubVersion::Epub2
+    } else if item_href(&items, "ncx").is_some() {
+        EpubVersion::Compatible
+    } else {
+        EpubVersion::Epub3
+    };
+    let vendor = Vendor::detect(Path::new(dir), &opf);
+
+    let mut pages = Vec::new();
+    let mut spine_properties = Vec::new();
+    if !cover_page {
+        // The cover image is not part of the reading order
+        pages.push(read_cover_image(&opf_dir, &items, (width, height))?);
+        spine_properties.push(None);
+    }
+    for (idref, properties) in itemrefs {
+        if idref == "nav" {
+            continue;
+        }
+        let href = item_href(&items, &idref)
+            .ok_or_else(|| anyhow!("no manifest item for spine entry {idref}"))?;
+        pages.push(read_part(&opf_dir, &href, &img_re, (width, height))?);
+        spine_properties.push(properties);
+    }
+    let is_rtl = opf.contains(r#"page-progression-direction="rtl""#)
+        || opf.contains(r#"<meta name="primary-writing-mode" content="horizontal-rl"/>"#);
+    // Forced spreads and rendition overrides stay with their pages
+    restore_spine_properties(&mut pages, &spine_properties, is_rtl, vendor)?;
+
+    // Printed page numbers stay with their pages
+    restore_labels(&mut pages, read_page_labels(&opf_dir, &items)?);
+
+    let metadata = Metadata {
+        title: dc_element(&opf, "title")?.ok_or_else(|| anyhow!("missing title"))?,
+        creator: dc_element(&opf, "creator")?,
+        publisher: dc_element(&opf, "publisher")?,
+        date: dc_element(&opf, "date")?,
+        is_rtl,
+        blank: None,
+        series: Regex::new(r#"<meta property="belongs-to-collection" id="series">(.*?)</meta>"#)?
+            .captures(&opf)
+            .or(Regex::new(r#"<meta name="calibre:series" content="(.*?)"/>"#)?.captures(&opf))
+            .map(|c| unescape_xml(&c[1])),
+        volume: Regex::new(r##"<meta refines="#series" property="group-position">(\d+)</meta>"##)?
+            .captures(&opf)
+            .or(
+                Regex::new(r#"<meta name="calibre:series_index" content="(\d+)"/>"#)?
+                    .captures(&opf),
+            )
+            .and_then(|c| c[1].parse().ok()),
+        pages: None,
+        cover: None,
+        rendition: read_rendition(&opf)?,
+        accessibility: read_accessibility(&opf, &pages)?,
+    };
+
+    Ok(GeneratedBook {
+        identifier: dc_element(&opf, "identifier")?.ok_or_else(|| anyhow!("missing identifier"))?,
+        language: dc_element(&opf, "language")?.unwrap_or_else(|| "ja-JP".to_string()),
+        metadata,
+        width,
+        height,
+        pages,
+        cover_page,
+        version,
+        vendor,
+        page_options: read_page_options(&opf)?,
+    })
+}
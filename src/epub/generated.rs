@@ -0,0 +1,334 @@
+use std::{fs::read_to_string, path::Path};
+
+use anyhow::Result;
+use pulldown_cmark::{html::push_html, Event, Options, Parser, Tag, TagEnd};
+
+use super::{
+    compat::EpubVersion,
+    converter::{escape_xml, Metadata},
+    images::Color,
+};
+
+/// A page that is not an image file padded to the canvas.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GeneratedPage {
+    /// Cover with the title, series and creator, drawn as an SVG image
+    Cover,
+    /// Title page listing the metadata, written as XHTML text
+    TitlePage,
+    /// Reflowable text read from the Markdown or XHTML file at the path of
+    /// the page
+    Text,
+}
+
+/// Returns whether the file is a Markdown or XHTML file that can be a text page.
+pub fn is_text_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|x| x.to_str())
+        .is_some_and(|x| matches!(x.to_ascii_lowercase().as_str(), "md" | "markdown" | "xhtml"))
+}
+
+fn is_markdown_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|x| x.to_str())
+        .is_some_and(|x| matches!(x.to_ascii_lowercase().as_str(), "md" | "markdown"))
+}
+
+/// Black or white, whichever reads better on `background`.
+fn text_color(background: Color) -> &'static str {
+    let [r, g, b] = background.0.map(u32::from);
+    if r * 299 + g * 587 + b * 114 > 128_000 {
+        "#000"
+    } else {
+        "#fff"
+    }
+}
+
+/// Breaks `text` into lines of at most `max_width`, counting full-width
+/// characters as two and others as one. Lines are broken at the last space if
+/// there is one, or else anywhere.
+fn wrap(text: &str, max_width: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut line = String::new();
+    let mut width = 0;
+    for c in text.chars() {
+        let w = if c.is_ascii() { 1 } else { 2 };
+        if width + w > max_width && !line.is_empty() {
+            let rest = match line.rfind(' ') {
+                Some(i) if c != ' ' => {
+                    let rest = line[i + 1..].to_string();
+                    line.truncate(i);
+                    rest
+                }
+                _ => String::new(),
+            };
+            lines.push(line.trim_end().to_string());
+            width = rest.chars().map(|c| if c.is_ascii() { 1 } else { 2 }).sum();
+            line = rest;
+        }
+        if line.is_empty() && c == ' ' {
+            continue;
+        }
+        line.push(c);
+        width += w;
+    }
+    if !line.is_empty() {
+        lines.push(line);
+    }
+    lines
+}
+
+/// The series and volume of the book, such as `Series Vol. 2`, unless the
+/// title says as much already.
+fn series_line(metadata: &Metadata) -> Option<String> {
+    let series = metadata.series.as_ref()?;
+    let line = match metadata.volume {
+        Some(volume) => format!("{series} Vol. {volume}"),
+        None => series.clone(),
+    };
+    (line != metadata.title).then_some(line)
+}
+
+/// Draws a cover of `width` x `height` with the title in the middle, the
+/// series above it and the creator below.
+pub fn cover_svg(metadata: &Metadata, width: u32, height: u32, background: Color) -> String {
+    let [r, g, b] = background.0;
+    let color = text_color(background);
+    let center = width / 2;
+    let title_size = (width / 12).max(1);
+    let small_size = (width / 24).max(1);
+
+    // Half a font size per column, keeping a margin on each side
+    let columns = (width * 8 / 10 / (title_size / 2).max(1)) as usize;
+    let lines = wrap(&metadata.title, columns.max(2));
+    let line_height = title_size * 5 / 4;
+    let count = u32::try_from(lines.len()).unwrap_or(u32::MAX);
+    let top = (height / 2).saturating_sub(line_height.saturating_mul(count) / 2) + title_size;
+    let title = lines
+        .iter()
+        .enumerate()
+        .map(|(i, line)| {
+            format!(
+                r#"<tspan x="{center}" y="{}">{}</tspan>"#,
+                top + line_height * u32::try_from(i).unwrap_or(0),
+                escape_xml(line)
+            )
+        })
+        .collect::<Vec<_>>()
+        .concat();
+
+    let series = series_line(metadata).map_or(String::new(), |x| {
+        format!(
+            r#"
+    <text x="{center}" y="{}" font-size="{small_size}">{}</text>"#,
+            height / 4,
+            escape_xml(&x)
+        )
+    });
+    let creator = metadata.creator.as_ref().map_or(String::new(), |x| {
+        format!(
+            r#"
+    <text x="{center}" y="{}" font-size="{small_size}">{}</text>"#,
+            height * 4 / 5,
+            escape_xml(x)
+        )
+    });
+
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8" ?>
+<svg xmlns="http://www.w3.org/2000/svg" version="1.1" width="{width}" height="{height}" viewBox="0 0 {width} {height}">
+    <rect width="{width}" height="{height}" fill="rgb({r},{g},{b})"/>
+    <g fill="{color}" font-family="serif" text-anchor="middle">
+    <text font-size="{title_size}">{title}</text>{series}{creator}
+    </g>
+</svg>"#
+    )
+}
+
+/// Body of a title page of `width` x `height` with the title, creator and
+/// series in the upper half and the publisher and date at the bottom.
+/// It is marked with the `titlepage` class, and the semantics of EPUB 3.
+pub fn title_page_body(
+    metadata: &Metadata,
+    width: u32,
+    height: u32,
+    background: Color,
+    version: EpubVersion,
+) -> String {
+    let [r, g, b] = background.0;
+    let color = text_color(background);
+    let title_size = (width / 16).max(1);
+    let small_size = (width / 32).max(1);
+    let paragraph = |text: &str| {
+        format!(
+            r#"
+            <p style="font-size: {small_size}px; margin-top: {small_size}px">{}</p>"#,
+            escape_xml(text)
+        )
+    };
+
+    let upper = [metadata.creator.clone(), series_line(metadata)]
+        .into_iter()
+        .flatten()
+        .map(|x| paragraph(&x))
+        .collect::<String>();
+    let lower = [metadata.publisher.as_ref(), metadata.date.as_ref()]
+        .into_iter()
+        .flatten()
+        .map(|x| paragraph(x))
+        .collect::<String>();
+
+    let epub_type = if version.is_epub3() {
+        r#" epub:type="titlepage""#
+    } else {
+        ""
+    };
+    format!(
+        r#"<body{epub_type} class="titlepage" style="background: rgb({r},{g},{b}); color: {color}; height: {height}px; text-align: center; width: {width}px">
+        <div style="left: 10%; position: absolute; top: 30%; width: 80%">
+            <h1 style="font-size: {title_size}px">{}</h1>{upper}
+        </div>
+        <div style="bottom: 10%; left: 10%; position: absolute; width: 80%">{lower}
+        </div>
+    </body>"#,
+        escape_xml(&metadata.title)
+    )
+}
+
+/// Returns the part file of a text page. Markdown is converted to XHTML
+/// titled `title`, with the document type `doctype`; an XHTML file is used as
+/// it is, so it must be a complete document.
+///
+/// # Errors
+///
+/// Returns an error if the file cannot be read.
+pub fn text_page(path: &Path, title: &str, doctype: &str) -> Result<String> {
+    let content = read_to_string(path)?;
+    if !is_markdown_file(path) {
+        return Ok(content);
+    }
+
+    // Raw HTML need not be well-formed XML, so it is shown as text instead,
+    // a block of it as a paragraph
+    let events = Parser::new_ext(
+        &content,
+        Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH,
+    )
+    .map(|x| match x {
+        Event::Start(Tag::HtmlBlock) => Event::Start(Tag::Paragraph),
+        Event::End(TagEnd::HtmlBlock) => Event::End(TagEnd::Paragraph),
+        Event::Html(html) | Event::InlineHtml(html) => Event::Text(html),
+        x => x,
+    });
+    let mut body = String::new();
+    push_html(&mut body, events);
+    Ok(format!(
+        r#"<?xml version="1.0" encoding="UTF-8" ?>
+{doctype}
+<html xmlns="http://www.w3.org/1999/xhtml">
+    <head>
+        <title>{}</title>
+    </head>
+    <body>
+{body}    </body>
+</html>"#,
+        escape_xml(title)
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::write;
+
+    use xml::reader::EventReader;
+
+    use super::*;
+    use crate::epub::testing::TestDir;
+
+    /// Fails unless `xml` is well-formed.
+    fn parse(xml: &str) -> Result<()> {
+        for event in EventReader::new(xml.as_bytes()) {
+            event?;
+        }
+        Ok(())
+    }
+
+    fn metadata() -> Result<Metadata> {
+        Ok(serde_json::from_str(
+            r#"{
+                "title": "Tom & Jerry <1>",
+                "creator": "A \"B\" C",
+                "publisher": "P&P",
+                "date": "2024-01-01",
+                "series": "Cats & Mice",
+                "volume": 2,
+                "is_rtl": false
+            }"#,
+        )?)
+    }
+
+    #[test]
+    fn markdown_with_raw_html_is_well_formed() -> Result<()> {
+        let dir = TestDir::new(&[])?;
+        let path = dir.path("colophon.md");
+        write(
+            &path,
+            "# Colophon\n\nFirst line<br>\nsecond line\n\n<div>\n<p>unclosed\n\n&copy; <b>2024</b>\n",
+        )?;
+        let page = text_page(&path, "Colophon & more", EpubVersion::Epub2.doctype())?;
+        parse(&page)?;
+        assert!(page.contains("First line&lt;br&gt;"));
+        Ok(())
+    }
+
+    #[test]
+    fn cover_shows_the_metadata_escaped() -> Result<()> {
+        let svg = cover_svg(&metadata()?, 600, 800, Color::BLACK);
+        parse(&svg)?;
+        assert!(svg.contains(r#"<rect width="600" height="800" fill="rgb(0,0,0)"/>"#));
+        assert!(svg.contains(r##"<g fill="#fff""##));
+        assert!(svg.contains("Tom &amp; Jerry &lt;1&gt;"));
+        assert!(svg.contains(">Cats &amp; Mice Vol. 2</text>"));
+        assert!(svg.contains(r">A &quot;B&quot; C</text>"));
+        assert!(!svg.contains("P&amp;P"));
+        Ok(())
+    }
+
+    #[test]
+    fn cover_leaves_out_what_the_title_says() -> Result<()> {
+        let metadata = Metadata {
+            title: "Series".to_string(),
+            series: Some("Series".to_string()),
+            volume: None,
+            creator: None,
+            ..metadata()?
+        };
+        let svg = cover_svg(&metadata, 600, 800, Color::WHITE);
+        parse(&svg)?;
+        assert_eq!(svg.matches("<text").count(), 1);
+        assert!(svg.contains(r##"<g fill="#000""##));
+        Ok(())
+    }
+
+    #[test]
+    fn title_page_shows_the_metadata_escaped() -> Result<()> {
+        let body = title_page_body(&metadata()?, 600, 800, Color::WHITE, EpubVersion::Epub3);
+        parse(&format!(
+            r#"<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops">{body}</html>"#
+        ))?;
+        assert!(body.starts_with(r#"<body epub:type="titlepage" class="titlepage""#));
+        assert!(body.contains(r#"<h1 style="font-size: 37px">Tom &amp; Jerry &lt;1&gt;</h1>"#));
+        for text in [
+            "A &quot;B&quot; C",
+            "Cats &amp; Mice Vol. 2",
+            "P&amp;P",
+            "2024-01-01",
+        ] {
+            assert!(body.contains(&format!("px\">{text}</p>")), "{text}");
+        }
+
+        let body = title_page_body(&metadata()?, 600, 800, Color::WHITE, EpubVersion::Epub2);
+        assert!(body.starts_with(r#"<body class="titlepage""#));
+        Ok(())
+    }
+}
@@ -1,112 +1,1030 @@
-use std::{thread::sleep, time::Duration};
+use std::{
+    cmp::Ordering,
+    fmt,
+    fs::{metadata, File},
+    io::BufWriter,
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+};
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Result};
 use glob::glob;
-use image::GenericImageView;
-use regex::Regex;
+use image::{
+    codecs::jpeg::JpegEncoder,
+    imageops::{replace, FilterType},
+    DynamicImage, GenericImageView, ImageFormat, Rgb, RgbImage, Rgba,
+};
 
-#[derive(Debug, Clone)]
+use super::{
+    accessibility::ALT_TEXT_FILE,
+    compat::EpubVersion,
+    decode::decode_image,
+    generated::{is_text_file, GeneratedPage},
+    pages::{PageEntry, PageSpread},
+    panels::{Panel, PANELS_FILE},
+    rendition::PageRendition,
+};
+
+#[derive(Debug, Clone, Default)]
 pub struct Image {
-    pub path: std::path::PathBuf,
+    pub path: PathBuf,
     pub file_name: String,
     pub width: u32,
     pub height: u32,
+    /// Side of the spread the page is forced onto
+    pub spread: Option<PageSpread>,
+    /// Printed page number of the page
+    pub label: Option<String>,
+    /// What the page is made up of, if not of the image at `path`
+    pub generated: Option<GeneratedPage>,
+    /// Rendition properties the page overrides the book's with
+    pub rendition: PageRendition,
+    /// Panels of the page in reading order, which readers can zoom into one
+    /// by one
+    pub panels: Vec<Panel>,
+    /// Text description of the page, for readers who cannot see it
+    pub alt: Option<String>,
+    /// Path of the file the page was found as, relative to the image
+    /// directory with `/` separators, as the sidecar files list it
+    pub source: Option<String>,
+    /// Whether the image at `path` is a page image read back from a book,
+    /// which is kept as it is if it already fits the page
+    pub encoded: bool,
+}
+
+/// Format the page images of a book are encoded in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PageFormat {
+    /// Lossless WebP
+    #[default]
+    Webp,
+    /// JPEG at the given quality, for EPUB 2 readers, which need not decode
+    /// WebP, or for books that must fit a size
+    Jpeg(u8),
+}
+
+impl PageFormat {
+    /// Quality EPUB 2 pages are encoded at.
+    pub const JPEG_QUALITY: u8 = 90;
+
+    #[must_use]
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Webp => "webp",
+            Self::Jpeg(_) => "jpg",
+        }
+    }
+
+    #[must_use]
+    pub fn media_type(self) -> &'static str {
+        match self {
+            Self::Webp => "image/webp",
+            Self::Jpeg(_) => "image/jpeg",
+        }
+    }
+
+    /// Writes `image` to `path` in this format.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if encoding or writing the image fails.
+    pub fn save(self, image: &RgbImage, path: &Path) -> Result<()> {
+        match self {
+            Self::Webp => image.save_with_format(path, ImageFormat::WebP)?,
+            Self::Jpeg(quality) => image.write_with_encoder(JpegEncoder::new_with_quality(
+                BufWriter::new(File::create(path)?),
+                quality,
+            ))?,
+        }
+        Ok(())
+    }
 }
 
 impl Image {
-    pub fn relative_path(&self) -> String {
-        format!("images/{}.webp", self.file_name)
-    }
-}
-
-fn extract_number(re: &Regex, path: &std::path::Path) -> Result<u32> {
-    let path_str = path
-        .to_str()
-        .ok_or_else(|| anyhow!("invalid path (not valid UTF-8)"))?;
-    let num = re
-        .captures(path_str)
-        .and_then(|c| c.get(2))
-        .ok_or_else(|| anyhow!("invalid file name: {}", path.display()))?
-        .as_str()
-        .parse::<u32>()
-        .map_err(|e| anyhow!("failed to parse number in file name: {e}"))?;
-    Ok(num)
-}
-
-pub fn sort_image_files(dir: &str) -> Result<Vec<Image>> {
-    let target_files = glob(&format!("{dir}/**/*"))?;
-    let re = Regex::new(r"/(\D*|.*\D)(\d{1,6})\.(jpe?g|JPE?G|png|PNG|webp|WEBP)$")?;
-    let mut sorted_files = target_files
-        .filter_map(Result::ok)
-        .filter(|x| x.to_str().is_some_and(|x| re.is_match(x)))
-        .collect::<Vec<_>>();
+    /// Path of the page image in the EPUB, relative to `OEBPS`, when pages
+    /// are encoded as `format`.
+    pub fn relative_path(&self, format: PageFormat) -> String {
+        match self.generated {
+            Some(GeneratedPage::Cover) => format!("images/{}.svg", self.file_name),
+            _ => format!("images/{}.{}", self.file_name, format.extension()),
+        }
+    }
+
+    /// Alternative text of the page image: its description, or else its
+    /// printed page number. A page with neither has an empty one, so that
+    /// screen readers skip it rather than read out a file name.
+    #[must_use]
+    pub fn alt_text(&self) -> String {
+        match (&self.alt, &self.label) {
+            (Some(alt), _) => alt.clone(),
+            (None, Some(label)) => format!("Page {label}"),
+            (None, None) => String::new(),
+        }
+    }
+
+    /// Whether the page is shown from an image, as opposed to text.
+    pub fn has_image(&self) -> bool {
+        !matches!(
+            self.generated,
+            Some(GeneratedPage::TitlePage | GeneratedPage::Text)
+        )
+    }
+
+    pub fn media_type(&self, format: PageFormat) -> &'static str {
+        match self.generated {
+            Some(GeneratedPage::Cover) => "image/svg+xml",
+            _ => format.media_type(),
+        }
+    }
+}
+
+/// How [`sort_image_files`] orders the pages of a book.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SortStrategy {
+    /// Natural order of the paths relative to the image directory, comparing
+    /// runs of digits by their value (`ch2/p9.png` < `ch10/p1.png`)
+    #[default]
+    Natural,
+    /// The last number in the file name, ignoring directories
+    LastNumber,
+    /// Byte order of the paths relative to the image directory
+    Lexical,
+    /// Modification time of the files, oldest first
+    Mtime,
+}
+
+impl FromStr for SortStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "natural" => Ok(Self::Natural),
+            "last-number" => Ok(Self::LastNumber),
+            "lexical" => Ok(Self::Lexical),
+            "mtime" => Ok(Self::Mtime),
+            _ => bail!(
+                "invalid sort strategy: {s} (expected natural, last-number, lexical or mtime)"
+            ),
+        }
+    }
+}
+
+/// Splits a string into runs of digits and runs of other characters.
+fn chunks(s: &str) -> Vec<(bool, &str)> {
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    let mut digit = false;
+    for (i, c) in s.char_indices() {
+        if i > 0 && c.is_ascii_digit() != digit {
+            chunks.push((digit, &s[start..i]));
+            start = i;
+        }
+        digit = c.is_ascii_digit();
+    }
+    if start < s.len() {
+        chunks.push((digit, &s[start..]));
+    }
+    chunks
+}
+
+/// Compares two runs of digits by value, however long they are.
+fn cmp_numbers(a: &str, b: &str) -> Ordering {
+    let a = a.trim_start_matches('0');
+    let b = b.trim_start_matches('0');
+    a.len().cmp(&b.len()).then_with(|| a.cmp(b))
+}
+
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let a = chunks(a);
+    let b = chunks(b);
+    let compare = |case_sensitive: bool| {
+        a.iter()
+            .zip(&b)
+            .map(|(x, y)| match (x, y) {
+                ((true, x), (true, y)) => cmp_numbers(x, y),
+                ((_, x), (_, y)) if case_sensitive => x.cmp(y),
+                ((_, x), (_, y)) => x.to_lowercase().cmp(&y.to_lowercase()),
+            })
+            .find(|x| *x != Ordering::Equal)
+            .unwrap_or_else(|| a.len().cmp(&b.len()))
+    };
+    // Case only tells apart names that are otherwise the same
+    compare(false).then_with(|| compare(true))
+}
+
+/// Compares paths component by component in natural order.
+fn natural_path_cmp(a: &Path, b: &Path) -> Ordering {
+    let a = a.components().collect::<Vec<_>>();
+    let b = b.components().collect::<Vec<_>>();
+    a.iter()
+        .zip(&b)
+        .map(|(x, y)| {
+            natural_cmp(
+                &x.as_os_str().to_string_lossy(),
+                &y.as_os_str().to_string_lossy(),
+            )
+        })
+        .find(|x| *x != Ordering::Equal)
+        .unwrap_or_else(|| a.len().cmp(&b.len()))
+}
+
+/// Returns the last run of digits in the file name, without the extension.
+fn last_number(path: &Path) -> Result<String> {
+    let stem = path
+        .file_stem()
+        .and_then(|x| x.to_str())
+        .ok_or_else(|| anyhow!("invalid file name: {}", path.display()))?;
+    chunks(stem)
+        .into_iter()
+        .rev()
+        .find_map(|(is_digit, x)| is_digit.then(|| x.to_string()))
+        .ok_or_else(|| anyhow!("no number in file name: {}", path.display()))
+}
+
+/// Files in the image directory that are read by the converter itself.
+const SIDECAR_FILES: [&str; 4] = ["metadata.json", "pages.txt", PANELS_FILE, ALT_TEXT_FILE];
+
+/// GIFs contribute their first frame. AVIF is left out, since the `image`
+/// crate has no decoder for it without `libdav1d`.
+fn is_image_file(path: &Path) -> bool {
+    path.extension().and_then(|x| x.to_str()).is_some_and(|x| {
+        matches!(
+            x.to_ascii_lowercase().as_str(),
+            "jpg" | "jpeg" | "png" | "webp" | "gif" | "tif" | "tiff" | "bmp"
+        )
+    })
+}
+
+/// Files found while collecting the pages of a book that did not become pages.
+#[derive(Debug, Default)]
+pub struct ScanReport {
+    /// Files that are not images of a supported format, or images left out
+    /// of an explicit page order
+    pub ignored: Vec<PathBuf>,
+    /// Images that could not be decoded and were skipped, with the reason
+    pub failed: Vec<(PathBuf, String)>,
+}
+
+/// `path` without its `.` components, which glob leaves out of the paths it
+/// finds, so that `./images` and `images` name the same files.
+fn normalized(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|x| *x != Component::CurDir)
+        .collect()
+}
+
+/// Path of `path`, found under `dir`, relative to `dir`.
+fn relative_to(dir: &str, path: &Path) -> Result<PathBuf> {
+    Ok(normalized(path)
+        .strip_prefix(normalized(Path::new(dir)))
+        .map_err(|_| anyhow!("{} is not under {dir}", path.display()))?
+        .to_path_buf())
+}
+
+/// A file found under the image directory, with its path relative to it.
+type FoundFile = (PathBuf, PathBuf);
+
+/// Returns the image files under `dir` and the other files next to them.
+fn find_image_files(dir: &str) -> Result<(Vec<FoundFile>, Vec<PathBuf>)> {
+    let mut images = Vec::new();
+    let mut others = Vec::new();
+    for path in glob(&format!("{}/**/*", glob::Pattern::escape(dir)))?.filter_map(Result::ok) {
+        if !path.is_file() {
+            continue;
+        }
+        let relative = relative_to(dir, &path)?;
+        if is_image_file(&path) {
+            images.push((path, relative));
+        } else if !SIDECAR_FILES.iter().any(|x| relative == Path::new(x)) {
+            others.push(path);
+        }
+    }
+    Ok((images, others))
+}
+
+/// Reads the dimensions of a single image file.
+///
+/// # Errors
+///
+/// Returns an error if the file cannot be opened or decoded.
+pub fn open_image_file(path: &Path, file_name: String) -> Result<Image> {
+    let image = decode_image(path)?;
+    Ok(Image {
+        path: path.to_path_buf(),
+        file_name,
+        width: image.width(),
+        height: image.height(),
+        ..Image::default()
+    })
+}
 
-    // Extract numbers for sorting, propagating errors
-    let mut numbered: Vec<(u32, std::path::PathBuf)> = sorted_files
+/// Returns whether `dir` contains any image file [`sort_image_files`] would pick up.
+///
+/// # Errors
+///
+/// Returns an error if the directory pattern is invalid.
+pub fn contains_image_files(dir: &str) -> Result<bool> {
+    Ok(!find_image_files(dir)?.0.is_empty())
+}
+
+/// Opens the pages in order, naming them after their position. Images that
+/// cannot be decoded are recorded in `report` and left out if `skip_bad` is
+/// set.
+fn open_pages<'a>(
+    pages: impl Iterator<Item = (FoundFile, Option<&'a PageEntry>)>,
+    skip_bad: bool,
+    report: &mut ScanReport,
+) -> Result<Vec<Image>> {
+    let mut images = Vec::new();
+    for ((path, relative), entry) in pages {
+        let source = Some(relative.to_string_lossy().replace('\\', "/"));
+        if is_text_file(&path) {
+            images.push(Image {
+                path,
+                file_name: format!("{:06}", images.len()),
+                generated: Some(GeneratedPage::Text),
+                rendition: entry.map(|x| x.rendition).unwrap_or_default(),
+                source,
+                ..Image::default()
+            });
+            continue;
+        }
+        match open_image_file(&path, format!("{:06}", images.len())) {
+            Ok(image) => images.push(Image {
+                spread: entry.and_then(|x| x.spread),
+                label: entry.and_then(|x| x.label.clone()),
+                rendition: entry.map(|x| x.rendition).unwrap_or_default(),
+                source,
+                ..image
+            }),
+            Err(e) if skip_bad => report.failed.push((path, e.to_string().trim().to_string())),
+            Err(e) => bail!("{}: {}", path.display(), e.to_string().trim()),
+        }
+    }
+    Ok(images)
+}
+
+/// Opens the pages listed in `order`, in that order, skipping the ones marked
+/// so and carrying over their attributes. Markdown and XHTML files become
+/// text pages where they are listed.
+fn ordered_image_files(
+    dir: &str,
+    order: &[PageEntry],
+    skip_bad: bool,
+    mut report: ScanReport,
+) -> Result<(Vec<Image>, ScanReport)> {
+    let missing = order
         .iter()
-        .map(|x| extract_number(&re, x).map(|n| (n, x.clone())))
-        .collect::<Result<Vec<_>>>()?;
-    numbered.sort_by_key(|(n, _)| *n);
-    sorted_files = numbered.into_iter().map(|(_, p)| p).collect();
+        .filter(|x| !Path::new(dir).join(&x.file).is_file())
+        .map(|x| x.file.as_str())
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        bail!("listed pages not found in {dir}: {}", missing.join(", "));
+    }
 
-    sorted_files
+    // Listed files are compared relative to `dir`, however either is spelled
+    let listed = order
         .iter()
-        .map(|x| match image::open(x.clone()) {
-            Ok(image) => {
-                let num = extract_number(&re, x)?;
-                Ok(Image {
-                    path: x.clone(),
-                    file_name: format!("{num:06}"),
-                    width: image.width(),
-                    height: image.height(),
-                })
+        .map(|x| normalized(Path::new(&x.file)))
+        .collect::<Vec<_>>();
+    let is_listed = |x: &Path| -> Result<bool> { Ok(listed.contains(&relative_to(dir, x)?)) };
+    let mut ignored = Vec::new();
+    for path in report.ignored {
+        if !(is_text_file(&path) && is_listed(&path)?) {
+            ignored.push(path);
+        }
+    }
+    let (files, _) = find_image_files(dir)?;
+    ignored.extend(
+        files
+            .into_iter()
+            .filter(|(_, relative)| !listed.contains(relative))
+            .map(|(x, _)| x),
+    );
+    report.ignored = ignored;
+
+    let pages = order.iter().filter(|x| !x.skip).map(|x| {
+        let file = (Path::new(dir).join(&x.file), normalized(Path::new(&x.file)));
+        (file, Some(x))
+    });
+    let images = open_pages(pages, skip_bad, &mut report)?;
+    Ok((images, report))
+}
+
+/// Finds the image files under `dir`, including subdirectories, and orders
+/// them with `strategy`, or as listed in `order` if given, which may list
+/// text pages too. Pages are named after their position in that order.
+///
+/// Other files are listed in the returned report, along with the images that
+/// could not be decoded if `skip_bad` is set.
+///
+/// # Errors
+///
+/// Returns an error if:
+/// - A file listed in `order` does not exist.
+/// - The strategy compares by number and a file name has no number, or two
+///   files have the same number.
+/// - An image cannot be opened or decoded, unless `skip_bad` is set.
+pub fn sort_image_files(
+    dir: &str,
+    strategy: SortStrategy,
+    order: Option<&[PageEntry]>,
+    skip_bad: bool,
+) -> Result<(Vec<Image>, ScanReport)> {
+    let (files, ignored) = find_image_files(dir)?;
+    let mut report = ScanReport {
+        ignored,
+        failed: Vec::new(),
+    };
+    if let Some(order) = order {
+        return ordered_image_files(dir, order, skip_bad, report);
+    }
+
+    let mut files = files;
+
+    match strategy {
+        SortStrategy::Natural => {
+            files.sort_by(|(_, a), (_, b)| natural_path_cmp(a, b));
+            if let Some(pair) = files
+                .windows(2)
+                .find(|x| natural_path_cmp(&x[0].1, &x[1].1) == Ordering::Equal)
+            {
+                bail!(
+                    "{} and {} have the same page number",
+                    pair[0].0.display(),
+                    pair[1].0.display()
+                );
             }
-            Err(e) => Err(e.into()),
+        }
+        SortStrategy::LastNumber => {
+            let mut numbered = files
+                .into_iter()
+                .map(|(x, relative)| Ok((last_number(&x)?, x, relative)))
+                .collect::<Result<Vec<_>>>()?;
+            numbered.sort_by(|(a, _, _), (b, _, _)| cmp_numbers(a, b));
+            if let Some(pair) = numbered
+                .windows(2)
+                .find(|x| cmp_numbers(&x[0].0, &x[1].0) == Ordering::Equal)
+            {
+                bail!(
+                    "{} and {} have the same page number {}",
+                    pair[0].1.display(),
+                    pair[1].1.display(),
+                    pair[0].0
+                );
+            }
+            files = numbered
+                .into_iter()
+                .map(|(_, x, relative)| (x, relative))
+                .collect();
+        }
+        SortStrategy::Lexical => files.sort_by(|(_, a), (_, b)| a.cmp(b)),
+        SortStrategy::Mtime => {
+            let mut timed = files
+                .into_iter()
+                .map(|(x, relative)| Ok((metadata(&x)?.modified()?, x, relative)))
+                .collect::<Result<Vec<_>>>()?;
+            timed.sort_by(|(a, _, x), (b, _, y)| a.cmp(b).then_with(|| natural_path_cmp(x, y)));
+            files = timed
+                .into_iter()
+                .map(|(_, x, relative)| (x, relative))
+                .collect();
+        }
+    }
+
+    let pages = files.into_iter().map(|x| (x, None));
+    let images = open_pages(pages, skip_bad, &mut report)?;
+    Ok((images, report))
+}
+
+/// An opaque RGB color.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Color(pub [u8; 3]);
+
+impl Color {
+    pub const WHITE: Self = Self([255, 255, 255]);
+    pub const BLACK: Self = Self([0, 0, 0]);
+}
+
+impl Default for Color {
+    fn default() -> Self {
+        Self::WHITE
+    }
+}
+
+impl FromStr for Color {
+    type Err = anyhow::Error;
+
+    /// Parses `white`, `black`, `#rgb` or `#rrggbb`.
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || anyhow!("invalid color: {s} (expected white, black, #rgb or #rrggbb)");
+        match s.to_ascii_lowercase().as_str() {
+            "white" => return Ok(Self::WHITE),
+            "black" => return Ok(Self::BLACK),
+            _ => {}
+        }
+        let hex = s.strip_prefix('#').ok_or_else(invalid)?;
+        let digits = hex
+            .chars()
+            .map(|x| x.to_digit(16).and_then(|x| u8::try_from(x).ok()))
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(invalid)?;
+        match digits[..] {
+            [r, g, b] => Ok(Self([r * 17, g * 17, b * 17])),
+            [r1, r2, g1, g2, b1, b2] => Ok(Self([r1 * 16 + r2, g1 * 16 + g2, b1 * 16 + b2])),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for Color {
+    /// Writes the color as `#rrggbb`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [r, g, b] = self.0;
+        write!(f, "#{r:02x}{g:02x}{b:02x}")
+    }
+}
+
+/// What the canvas around a page is filled with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Padding {
+    Color(Color),
+    /// The average color of the edge of the page
+    Auto,
+}
+
+impl Default for Padding {
+    fn default() -> Self {
+        Self::Color(Color::WHITE)
+    }
+}
+
+impl FromStr for Padding {
+    type Err = anyhow::Error;
+
+    /// Parses `auto` or a color as [`Color`] does.
+    fn from_str(s: &str) -> Result<Self> {
+        if s.eq_ignore_ascii_case("auto") {
+            Ok(Self::Auto)
+        } else {
+            s.parse().map(Self::Color)
+        }
+    }
+}
+
+impl fmt::Display for Padding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Color(color) => color.fmt(f),
+            Self::Auto => f.write_str("auto"),
+        }
+    }
+}
+
+/// Resampling filter used to shrink pages.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ResizeFilter {
+    Nearest,
+    Triangle,
+    CatmullRom,
+    Gaussian,
+    #[default]
+    Lanczos3,
+}
+
+impl ResizeFilter {
+    fn filter_type(self) -> FilterType {
+        match self {
+            Self::Nearest => FilterType::Nearest,
+            Self::Triangle => FilterType::Triangle,
+            Self::CatmullRom => FilterType::CatmullRom,
+            Self::Gaussian => FilterType::Gaussian,
+            Self::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+}
+
+impl FromStr for ResizeFilter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "nearest" => Ok(Self::Nearest),
+            "triangle" => Ok(Self::Triangle),
+            "catmull-rom" => Ok(Self::CatmullRom),
+            "gaussian" => Ok(Self::Gaussian),
+            "lanczos3" => Ok(Self::Lanczos3),
+            _ => bail!(
+                "invalid filter: {s} (expected nearest, triangle, catmull-rom, gaussian or lanczos3)"
+            ),
+        }
+    }
+}
+
+/// Unsharp masking applied to pages after they are shrunk.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Unsharp {
+    /// Standard deviation of the blur, in pixels
+    pub sigma: f32,
+    /// Minimum difference from the blurred pixel for a pixel to be sharpened
+    pub threshold: i32,
+}
+
+impl FromStr for Unsharp {
+    type Err = anyhow::Error;
+
+    /// Parses `SIGMA` or `SIGMA,THRESHOLD`.
+    fn from_str(s: &str) -> Result<Self> {
+        let (sigma, threshold) = s.split_once(',').unwrap_or((s, "0"));
+        let sigma = sigma
+            .trim()
+            .parse::<f32>()
+            .map_err(|e| anyhow!("invalid sigma: {e}"))?;
+        if sigma <= 0.0 {
+            bail!("sigma must be greater than 0");
+        }
+        Ok(Self {
+            sigma,
+            threshold: threshold
+                .trim()
+                .parse()
+                .map_err(|e| anyhow!("invalid threshold: {e}"))?,
         })
-        .collect::<Result<Vec<_>>>()
+    }
+}
+
+/// How each page is turned into an image of the book.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PageOptions {
+    /// Color transparent areas are composited over
+    pub background: Color,
+    /// Fill of the canvas around pages smaller than it
+    pub padding: Padding,
+    /// Keep every page at its own size instead of padding all of them to
+    /// the size of the largest one
+    pub native_size: bool,
+    /// Longest side a page is shrunk to
+    pub max_dimension: Option<u32>,
+    /// Number of pixels a page is shrunk to
+    pub max_pixels: Option<u64>,
+    pub filter: ResizeFilter,
+    /// Sharpening applied to pages that were shrunk
+    pub unsharp: Option<Unsharp>,
+    /// Quality pages are encoded at as lossy JPEG, to make the book smaller
+    pub quality: Option<u8>,
 }
 
+impl PageOptions {
+    /// Format the pages of a `version` book are encoded in: JPEG if a
+    /// quality is set, or else the format of the version.
+    #[must_use]
+    pub fn page_format(&self, version: EpubVersion) -> PageFormat {
+        self.quality.map_or(version.page_format(), PageFormat::Jpeg)
+    }
+
+    /// Returns the size a `width` x `height` page is shrunk to by the
+    /// resolution limits, keeping the aspect ratio.
+    #[must_use]
+    #[expect(
+        clippy::cast_possible_truncation,
+        clippy::cast_precision_loss,
+        clippy::cast_sign_loss,
+        reason = "the scaled size is no larger than the original one"
+    )]
+    pub fn scaled_size(&self, width: u32, height: u32) -> (u32, u32) {
+        let mut scale = 1.0_f64;
+        if let Some(max) = self.max_dimension {
+            scale = scale.min(f64::from(max) / f64::from(width.max(height)));
+        }
+        if let Some(max) = self.max_pixels {
+            scale = scale.min((max as f64 / (f64::from(width) * f64::from(height))).sqrt());
+        }
+        if scale >= 1.0 {
+            return (width, height);
+        }
+        let resize = |x: u32| ((f64::from(x) * scale).round() as u32).max(1);
+        (resize(width), resize(height))
+    }
+}
+
+/// Composites the image over `background`, dropping its alpha channel.
+fn flatten(image: &DynamicImage, background: Color) -> RgbImage {
+    if !image.color().has_alpha() {
+        return image.to_rgb8();
+    }
+    let rgba = image.to_rgba8();
+    RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let Rgba([r, g, b, a]) = *rgba.get_pixel(x, y);
+        let blend = |c: u8, bg: u8| {
+            let value =
+                (u32::from(c) * u32::from(a) + u32::from(bg) * (255 - u32::from(a)) + 127) / 255;
+            u8::try_from(value).unwrap_or(u8::MAX)
+        };
+        Rgb([
+            blend(r, background.0[0]),
+            blend(g, background.0[1]),
+            blend(b, background.0[2]),
+        ])
+    })
+}
+
+/// Returns the average color of the outermost pixels of the image.
+fn edge_color(image: &RgbImage) -> Color {
+    let (width, height) = image.dimensions();
+    let mut sum = [0u64; 3];
+    let mut count = 0u64;
+    for (x, y, pixel) in image.enumerate_pixels() {
+        if x == 0 || y == 0 || x + 1 == width || y + 1 == height {
+            for (s, c) in sum.iter_mut().zip(pixel.0) {
+                *s += u64::from(c);
+            }
+            count += 1;
+        }
+    }
+    if count == 0 {
+        return Color::WHITE;
+    }
+    Color(sum.map(|x| u8::try_from(x / count).unwrap_or(u8::MAX)))
+}
+
+/// Writes the page centered on a `max_width` x `max_height` canvas to
+/// `out_path` as `format`, shrinking it first if it does not fit.
+///
+/// # Errors
+///
+/// Returns an error if the image cannot be decoded or the output cannot be written.
 pub fn padding_image_file(
     image_file: &Image,
     max_width: u32,
     max_height: u32,
     out_path: &str,
+    options: &PageOptions,
+    format: PageFormat,
 ) -> Result<Image> {
     let mut padded = image_file.clone();
 
-    let width = padded.width;
-    let height = padded.height;
-    let width_diff = max_width - width;
-    let height_diff = max_height - height;
-    let padding_width = (width_diff / 2, width_diff - width_diff / 2);
-    let padding_height = (height_diff / 2, height_diff - height_diff / 2);
-    let img = image::open(padded.path.clone())?;
-    let mut imgbuf: image::ImageBuffer<image::Rgb<u8>, Vec<u8>> =
-        image::ImageBuffer::new(max_width, max_height);
-    for x in 0..max_width {
-        for y in 0..max_height {
-            if (x < padding_width.0 || x >= max_width - padding_width.1)
-                || (y < padding_height.0 || y >= max_height - padding_height.1)
-            {
-                imgbuf.put_pixel(x, y, image::Rgb([255, 255, 255]));
-            } else {
-                let rgb = img.get_pixel(x - padding_width.0, y - padding_height.0).0;
-                imgbuf.put_pixel(x, y, image::Rgb([rgb[0], rgb[1], rgb[2]]));
-            }
-        }
+    // Shrink images beyond the resolution limits or the canvas, keeping the
+    // aspect ratio
+    let mut img = decode_image(&padded.path)?;
+    let (width, height) = options.scaled_size(img.width(), img.height());
+    let filter = options.filter.filter_type();
+    let resized = if width > max_width || height > max_height {
+        img = img.resize(max_width, max_height, filter);
+        true
+    } else if (width, height) != img.dimensions() {
+        img = img.resize_exact(width, height, filter);
+        true
+    } else {
+        false
+    };
+    if let Some(unsharp) = options.unsharp.filter(|_| resized) {
+        img = img.unsharpen(unsharp.sigma, unsharp.threshold);
     }
-    image::save_buffer(
-        out_path,
-        &imgbuf,
-        max_width,
-        max_height,
-        image::ColorType::Rgb8,
-    )?;
+    let page = flatten(&img, options.background);
+
+    let padding = match options.padding {
+        Padding::Color(color) => color,
+        Padding::Auto => edge_color(&page),
+    };
+    let mut canvas = RgbImage::from_pixel(max_width, max_height, Rgb(padding.0));
+    replace(
+        &mut canvas,
+        &page,
+        i64::from((max_width - page.width()) / 2),
+        i64::from((max_height - page.height()) / 2),
+    );
+    format.save(&canvas, Path::new(out_path))?;
     padded.width = max_width;
     padded.height = max_height;
-    if out_path.contains("blank") {
-        println!("{out_path}");
-        sleep(Duration::from_secs(200));
-    }
     Ok(padded)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs::write;
+
+    use super::*;
+    use crate::epub::{pages::read_pages_txt, testing::TestDir};
+
+    /// File names of `paths`, to compare however the directory was spelled.
+    fn file_names<'a>(paths: impl IntoIterator<Item = &'a PathBuf>) -> Vec<String> {
+        paths
+            .into_iter()
+            .filter_map(|x| x.file_name())
+            .map(|x| x.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn listed_order_in_dot_dir() -> Result<()> {
+        let dir = TestDir::new(&["a.png", "b.png", "c.png"])?;
+        write(dir.path("pages.txt"), "c.png\n./a.png\n")?;
+        let order = read_pages_txt(&dir.0)?;
+        let (images, report) =
+            sort_image_files(&dir.0, SortStrategy::Natural, order.as_deref(), false)?;
+        assert_eq!(
+            file_names(images.iter().map(|x| &x.path)),
+            ["c.png", "a.png"]
+        );
+        assert_eq!(
+            images
+                .iter()
+                .map(|x| x.source.as_deref())
+                .collect::<Vec<_>>(),
+            [Some("c.png"), Some("a.png")]
+        );
+        assert_eq!(file_names(&report.ignored), ["b.png"]);
+        Ok(())
+    }
+
+    #[test]
+    fn sidecar_files_are_not_reported_in_dot_dir() -> Result<()> {
+        let dir = TestDir::new(&["ch10/1.png", "ch2/1.png"])?;
+        for name in SIDECAR_FILES {
+            write(dir.path(name), "{}")?;
+        }
+        write(dir.path("notes.txt"), "")?;
+        write(dir.path("ch2/2.avif"), "")?;
+        let (images, report) = sort_image_files(&dir.0, SortStrategy::Natural, None, false)?;
+        assert_eq!(
+            images
+                .iter()
+                .map(|x| relative_to(&dir.0, &x.path))
+                .collect::<Result<Vec<_>>>()?,
+            [Path::new("ch2/1.png"), Path::new("ch10/1.png")]
+        );
+        assert_eq!(file_names(&report.ignored), ["2.avif", "notes.txt"]);
+        Ok(())
+    }
+
+    #[test]
+    fn natural_order_compares_numbers_by_value() {
+        let mut names = ["p10", "p9", "p0000001", "p12345678", "P2", "p2"];
+        names.sort_by(|a, b| natural_cmp(a, b));
+        assert_eq!(names, ["p0000001", "P2", "p2", "p9", "p10", "p12345678"]);
+        assert_eq!(natural_cmp("12345678901234567890", "9"), Ordering::Greater);
+        assert_eq!(natural_cmp("007", "7"), Ordering::Equal);
+    }
+
+    #[test]
+    fn natural_order_goes_by_directory_first() {
+        let mut paths = ["ch10/p1.png", "ch2/p10.png", "ch2/p9.png", "cover.png"].map(Path::new);
+        paths.sort_by(|a, b| natural_path_cmp(a, b));
+        assert_eq!(
+            paths,
+            ["ch2/p9.png", "ch2/p10.png", "ch10/p1.png", "cover.png"].map(Path::new)
+        );
+    }
+
+    #[test]
+    fn same_page_numbers_are_an_error() -> Result<()> {
+        let dir = TestDir::new(&["p1.png", "p01.png"])?;
+        let error = sort_image_files(&dir.0, SortStrategy::Natural, None, false)
+            .err()
+            .map(|x| x.to_string());
+        assert!(error.is_some_and(|x| x.contains("have the same page number")));
+
+        let dir = TestDir::new(&["a/page-3.png", "b/scan-003.png"])?;
+        let error = sort_image_files(&dir.0, SortStrategy::LastNumber, None, false)
+            .err()
+            .map(|x| x.to_string());
+        assert!(error.is_some_and(|x| {
+            x.contains("page-3.png") && x.contains("scan-003.png") && x.contains("same page number")
+        }));
+        Ok(())
+    }
+
+    #[test]
+    fn colors_are_parsed() -> Result<()> {
+        assert_eq!("white".parse::<Color>()?, Color::WHITE);
+        assert_eq!("Black".parse::<Color>()?, Color::BLACK);
+        assert_eq!("#f80".parse::<Color>()?, Color([0xff, 0x88, 0x00]));
+        assert_eq!("#1A2b3C".parse::<Color>()?, Color([0x1a, 0x2b, 0x3c]));
+        for invalid in ["red", "#12", "#12345", "#ggg", "123456", "#1234567"] {
+            assert!(invalid.parse::<Color>().is_err(), "{invalid}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn pages_within_the_limits_keep_their_size() {
+        assert_eq!(PageOptions::default().scaled_size(4000, 3000), (4000, 3000));
+        let options = PageOptions {
+            max_dimension: Some(2000),
+            max_pixels: Some(4_000_000),
+            ..PageOptions::default()
+        };
+        assert_eq!(options.scaled_size(1600, 1200), (1600, 1200));
+    }
+
+    #[test]
+    fn pages_are_shrunk_to_the_tighter_limit() {
+        let options = PageOptions {
+            max_dimension: Some(2000),
+            ..PageOptions::default()
+        };
+        assert_eq!(options.scaled_size(4000, 3000), (2000, 1500));
+        assert_eq!(options.scaled_size(1000, 3000), (667, 2000));
+        let options = PageOptions {
+            max_dimension: Some(2000),
+            max_pixels: Some(1_200_000),
+            ..PageOptions::default()
+        };
+        assert_eq!(options.scaled_size(4000, 3000), (1265, 949));
+        assert_eq!(options.scaled_size(4000, 100), (2000, 50));
+    }
+
+    #[test]
+    fn shrunk_pages_are_at_least_a_pixel_wide() {
+        let options = PageOptions {
+            max_dimension: Some(100),
+            ..PageOptions::default()
+        };
+        assert_eq!(options.scaled_size(10_000, 10), (100, 1));
+    }
+
+    #[test]
+    fn transparency_is_composited_over_the_background() {
+        let image = DynamicImage::ImageRgba8(image::RgbaImage::from_fn(3, 1, |x, _| {
+            Rgba([255, 0, 0, [0, 128, 255][x as usize]])
+        }));
+        let flat = flatten(&image, Color::BLACK);
+        assert_eq!(flat.get_pixel(0, 0), &Rgb([0, 0, 0]));
+        assert_eq!(flat.get_pixel(1, 0), &Rgb([128, 0, 0]));
+        assert_eq!(flat.get_pixel(2, 0), &Rgb([255, 0, 0]));
+        let flat = flatten(&image, Color([0, 0, 255]));
+        assert_eq!(flat.get_pixel(0, 0), &Rgb([0, 0, 255]));
+        assert_eq!(flat.get_pixel(1, 0), &Rgb([128, 0, 127]));
+
+        let opaque = DynamicImage::ImageRgb8(RgbImage::from_pixel(2, 2, Rgb([1, 2, 3])));
+        assert_eq!(flatten(&opaque, Color::BLACK), opaque.to_rgb8());
+    }
+
+    #[test]
+    fn edge_color_is_the_average_of_the_outermost_pixels() {
+        // The inside of the image does not count
+        let image = RgbImage::from_fn(5, 5, |x, y| {
+            if (1..4).contains(&x) && (1..4).contains(&y) {
+                Rgb([255, 0, 0])
+            } else {
+                Rgb([0, 0, 200])
+            }
+        });
+        assert_eq!(edge_color(&image), Color([0, 0, 200]));
+
+        let image = RgbImage::from_fn(2, 1, |x, _| Rgb([[0, 100][x as usize], 50, 255]));
+        assert_eq!(edge_color(&image), Color([50, 50, 255]));
+        assert_eq!(edge_color(&RgbImage::new(0, 0)), Color::WHITE);
+    }
+
+    #[test]
+    fn pages_are_padded_with_their_edge_color() -> Result<()> {
+        let dir = TestDir::new(&[])?;
+        let path = dir.path("page.png");
+        image::RgbaImage::from_fn(20, 40, |x, y| {
+            if (x, y) == (10, 20) {
+                Rgba([255, 255, 255, 0])
+            } else {
+                Rgba([0, 150, 0, 255])
+            }
+        })
+        .save(&path)?;
+        let page = open_image_file(&path, "000001".to_string())?;
+        let out = dir.path("out.webp").to_string_lossy().into_owned();
+        let padded = padding_image_file(
+            &page,
+            60,
+            40,
+            &out,
+            &PageOptions {
+                background: Color([0, 0, 255]),
+                padding: Padding::Auto,
+                ..PageOptions::default()
+            },
+            PageFormat::Webp,
+        )?;
+        assert_eq!((padded.width, padded.height), (60, 40));
+
+        let written = image::open(&out)?.to_rgb8();
+        assert_eq!(written.dimensions(), (60, 40));
+        // Padding on either side, the page centered, and its transparent
+        // pixel on the background
+        assert_eq!(written.get_pixel(0, 0), &Rgb([0, 150, 0]));
+        assert_eq!(written.get_pixel(59, 39), &Rgb([0, 150, 0]));
+        assert_eq!(written.get_pixel(30, 20), &Rgb([0, 0, 255]));
+
+        padding_image_file(
+            &page,
+            60,
+            40,
+            &out,
+            &PageOptions::default(),
+            PageFormat::Webp,
+        )?;
+        let written = image::open(&out)?.to_rgb8();
+        assert_eq!(written.get_pixel(0, 0), &Rgb([255, 255, 255]));
+        assert_eq!(written.get_pixel(20, 0), &Rgb([0, 150, 0]));
+        assert_eq!(written.get_pixel(30, 20), &Rgb([255, 255, 255]));
+        Ok(())
+    }
+}
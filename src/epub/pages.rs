@@ -0,0 +1,257 @@
+use std::{fs::read_to_string, path::Path};
+
+use anyhow::{anyhow, bail, Result};
+use serde::Deserialize;
+
+use super::rendition::{Layout, Orientation, PageRendition, Spread};
+
+/// Which side of a two-page spread a page is shown on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PageSpread {
+    Left,
+    Right,
+    /// Shown alone, centered across the spread
+    Center,
+}
+
+impl PageSpread {
+    fn parse(value: &str) -> Result<Self> {
+        match value {
+            "left" => Ok(Self::Left),
+            "right" => Ok(Self::Right),
+            "center" => Ok(Self::Center),
+            _ => bail!("invalid spread: {value} (expected left, right or center)"),
+        }
+    }
+}
+
+/// How pages without a label of their own are numbered in the page list.
+///
+/// Pages are counted from the first one after the cover. The front matter
+/// is numbered in lowercase roman numerals and the rest from 1, continuing
+/// from the last page labelled with a number.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PageNumbering {
+    /// Number of pages after the cover that are front matter
+    pub front_matter: usize,
+}
+
+impl PageNumbering {
+    /// Returns the labels of the pages after the cover, given the labels
+    /// they have of their own.
+    #[must_use]
+    pub fn labels(&self, labels: &[Option<String>]) -> Vec<String> {
+        let mut number = 1;
+        labels
+            .iter()
+            .enumerate()
+            .map(|(i, label)| match label {
+                Some(label) => {
+                    if let Ok(n) = label.parse::<usize>() {
+                        number = n + 1;
+                    }
+                    label.clone()
+                }
+                None if i < self.front_matter => roman_numeral(i + 1),
+                None => {
+                    let label = number.to_string();
+                    number += 1;
+                    label
+                }
+            })
+            .collect()
+    }
+}
+
+/// Writes `n` in lowercase roman numerals, as front matter is numbered.
+fn roman_numeral(mut n: usize) -> String {
+    const NUMERALS: [(usize, &str); 13] = [
+        (1000, "m"),
+        (900, "cm"),
+        (500, "d"),
+        (400, "cd"),
+        (100, "c"),
+        (90, "xc"),
+        (50, "l"),
+        (40, "xl"),
+        (10, "x"),
+        (9, "ix"),
+        (5, "v"),
+        (4, "iv"),
+        (1, "i"),
+    ];
+    let mut numeral = String::new();
+    for (value, letters) in NUMERALS {
+        while n >= value {
+            numeral.push_str(letters);
+            n -= value;
+        }
+    }
+    numeral
+}
+
+/// A page listed in `pages.txt` or the `pages` array of metadata.json.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(from = "PageEntryJson")]
+pub struct PageEntry {
+    /// Path of the image, relative to the image directory
+    pub file: String,
+    /// Leave the page out of the book
+    pub skip: bool,
+    pub spread: Option<PageSpread>,
+    /// Printed page number of the page
+    pub label: Option<String>,
+    /// Rendition properties the page overrides the book's with
+    pub rendition: PageRendition,
+}
+
+/// A page in metadata.json is either a file name or an object with attributes.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PageEntryJson {
+    File(String),
+    Entry {
+        file: String,
+        #[serde(default)]
+        skip: bool,
+        spread: Option<PageSpread>,
+        label: Option<String>,
+        orientation: Option<Orientation>,
+        synthetic_spread: Option<Spread>,
+        layout: Option<Layout>,
+    },
+}
+
+impl From<PageEntryJson> for PageEntry {
+    fn from(value: PageEntryJson) -> Self {
+        match value {
+            PageEntryJson::File(file) => Self {
+                file,
+                skip: false,
+                spread: None,
+                label: None,
+                rendition: PageRendition::default(),
+            },
+            PageEntryJson::Entry {
+                file,
+                skip,
+                spread,
+                label,
+                orientation,
+                synthetic_spread,
+                layout,
+            } => Self {
+                file,
+                skip,
+                spread,
+                label,
+                rendition: PageRendition {
+                    orientation,
+                    spread: synthetic_spread,
+                    layout,
+                },
+            },
+        }
+    }
+}
+
+/// Parses `pages.txt`: one file per line, optionally followed by
+/// whitespace-separated attributes (`skip`, `spread=left|right|center`,
+/// `label=...`, and the rendition overrides `orientation=...`,
+/// `synthetic_spread=...` and `layout=...`). Empty lines and lines starting with `#` are ignored.
+///
+/// # Errors
+///
+/// Returns an error if an attribute value is invalid.
+pub fn parse_pages_txt(content: &str) -> Result<Vec<PageEntry>> {
+    let mut entries = Vec::new();
+    for (n, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut entry = PageEntry {
+            file: String::new(),
+            skip: false,
+            spread: None,
+            label: None,
+            rendition: PageRendition::default(),
+        };
+        let invalid = |e: anyhow::Error| anyhow!("pages.txt line {}: {e}", n + 1);
+        // Attributes are taken from the end of the line, so that the file
+        // name itself may contain spaces
+        let mut rest = line;
+        while let Some((head, token)) = rest.rsplit_once(char::is_whitespace) {
+            match token.split_once('=') {
+                None if token == "skip" => entry.skip = true,
+                Some(("spread", value)) => {
+                    entry.spread = Some(PageSpread::parse(value).map_err(invalid)?);
+                }
+                Some(("label", value)) => entry.label = Some(value.to_string()),
+                Some(("orientation", value)) => {
+                    entry.rendition.orientation = Some(value.parse().map_err(invalid)?);
+                }
+                Some(("synthetic_spread", value)) => {
+                    entry.rendition.spread = Some(value.parse().map_err(invalid)?);
+                }
+                Some(("layout", value)) => {
+                    entry.rendition.layout = Some(value.parse().map_err(invalid)?);
+                }
+                _ => break,
+            }
+            rest = head.trim_end();
+        }
+        entry.file = rest.to_string();
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// Reads `pages.txt` in the image directory, if there is one.
+///
+/// # Errors
+///
+/// Returns an error if the file cannot be read or parsed.
+pub fn read_pages_txt(image_dir: &str) -> Result<Option<Vec<PageEntry>>> {
+    let path = Path::new(image_dir).join("pages.txt");
+    if !path.exists() {
+        return Ok(None);
+    }
+    parse_pages_txt(&read_to_string(path)?).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn front_matter_is_numbered_in_roman_numerals() {
+        let numbering = PageNumbering { front_matter: 3 };
+        assert_eq!(
+            numbering.labels(&[None, None, None, None, None]),
+            ["i", "ii", "iii", "1", "2"]
+        );
+    }
+
+    #[test]
+    fn numbered_labels_continue_the_count() {
+        let numbering = PageNumbering { front_matter: 2 };
+        let labels = [None, None, None, Some("10"), None, Some("x"), None];
+        let labels = labels.map(|x| x.map(str::to_string));
+        assert_eq!(
+            numbering.labels(&labels),
+            ["i", "ii", "1", "10", "11", "x", "12"]
+        );
+    }
+
+    #[test]
+    fn roman_numerals_are_subtractive() {
+        let numerals = [1, 4, 9, 14, 40, 90, 400, 1994].map(roman_numeral);
+        assert_eq!(
+            numerals,
+            ["i", "iv", "ix", "xiv", "xl", "xc", "cd", "mcmxciv"]
+        );
+    }
+}
@@ -0,0 +1,268 @@
+use std::{collections::HashMap, fs::File, io::BufReader, path::Path};
+
+use anyhow::{bail, Result};
+use serde_json::from_reader;
+
+use super::{compat::EpubVersion, vendor::Vendor};
+
+/// Lists the panels of pages, relative to the image directory.
+pub const PANELS_FILE: &str = "panels.json";
+
+/// A panel of a page, in fractions of the page's width and height.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Panel {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+impl Panel {
+    /// The panel at `[x, y, width, height]` pixels of a `image_width` x
+    /// `image_height` image.
+    fn from_pixels([x, y, width, height]: [u32; 4], image_width: u32, image_height: u32) -> Self {
+        let (w, h) = (f64::from(image_width), f64::from(image_height));
+        Self {
+            x: f64::from(x) / w,
+            y: f64::from(y) / h,
+            width: f64::from(width) / w,
+            height: f64::from(height) / h,
+        }
+    }
+
+    /// The same panel of an image placed at `left`, `top` with a size of
+    /// `width` x `height` pixels on a `canvas_width` x `canvas_height` page.
+    #[must_use]
+    pub fn placed(
+        self,
+        (left, top): (u32, u32),
+        (width, height): (u32, u32),
+        (canvas_width, canvas_height): (u32, u32),
+    ) -> Self {
+        let (cw, ch) = (f64::from(canvas_width), f64::from(canvas_height));
+        Self {
+            x: (f64::from(left) + self.x * f64::from(width)) / cw,
+            y: (f64::from(top) + self.y * f64::from(height)) / ch,
+            width: self.width * f64::from(width) / cw,
+            height: self.height * f64::from(height) / ch,
+        }
+    }
+
+    /// The panel in whole pixels of a `width` x `height` page, as
+    /// `[x, y, width, height]`.
+    #[must_use]
+    #[expect(
+        clippy::cast_possible_truncation,
+        clippy::cast_sign_loss,
+        reason = "the panel lies within the page"
+    )]
+    pub fn pixels(self, width: u32, height: u32) -> [u32; 4] {
+        let (w, h) = (f64::from(width), f64::from(height));
+        [
+            (self.x * w).round() as u32,
+            (self.y * h).round() as u32,
+            (self.width * w).round() as u32,
+            (self.height * h).round() as u32,
+        ]
+    }
+}
+
+/// Reads the panels listed in `panels.json` in the image directory, by file
+/// path relative to the directory, in `[x, y, width, height]` pixels of the
+/// image.
+///
+/// # Errors
+///
+/// Returns an error if the file exists but cannot be read or parsed.
+pub fn read_panels_json(image_dir: &str) -> Result<HashMap<String, Vec<[u32; 4]>>> {
+    let path = Path::new(image_dir).join(PANELS_FILE);
+    if !path.exists() {
+        return Ok(HashMap::new());
+    }
+    Ok(from_reader(BufReader::new(File::open(path)?))?)
+}
+
+/// Converts the panels listed for a `width` x `height` image to fractions of it.
+///
+/// # Errors
+///
+/// Returns an error if a panel is empty or does not lie within the image.
+pub fn listed_panels(rects: &[[u32; 4]], width: u32, height: u32) -> Result<Vec<Panel>> {
+    rects
+        .iter()
+        .map(|&[x, y, w, h]| {
+            let inside = u64::from(x) + u64::from(w) <= u64::from(width)
+                && u64::from(y) + u64::from(h) <= u64::from(height);
+            if w == 0 || h == 0 || !inside {
+                bail!("panel [{x}, {y}, {w}, {h}] does not lie within the {width}x{height} image");
+            }
+            Ok(Panel::from_pixels([x, y, w, h], width, height))
+        })
+        .collect()
+}
+
+/// Markup of the panels of a `width` x `height` page showing the image at
+/// `src`: an `epub:type="panel"` region per panel and, for Kindle, the
+/// region magnification target that zooms into it. EPUB 2 has neither.
+pub fn panel_markup(
+    panels: &[Panel],
+    (width, height): (u32, u32),
+    src: &str,
+    version: EpubVersion,
+    vendor: Vendor,
+) -> String {
+    if !version.is_epub3() {
+        return String::new();
+    }
+    panels
+        .iter()
+        .enumerate()
+        .map(|(i, panel)| {
+            let n = i + 1;
+            let [left, top, panel_width, panel_height] = panel.pixels(width, height);
+            let region = format!(
+                r#"
+        <div id="panel-{n}" epub:type="panel" style="height: {panel_height}px; left: {left}px; position: absolute; top: {top}px; width: {panel_width}px">{}</div>"#,
+                if vendor == Vendor::Kindle {
+                    format!(
+                        r#"<a class="app-amzn-magnify" data-app-amzn-magnify='{{"targetId":"panel-{n}-magTarget","ordinal":{n}}}'></a>"#
+                    )
+                } else {
+                    String::new()
+                }
+            );
+            if vendor != Vendor::Kindle {
+                return region;
+            }
+            // The panel is zoomed to fill the page, keeping its aspect ratio
+            let [tx, ty, tw, th, iw, ih, ix, iy] =
+                magnified(*panel, width, height);
+            format!(
+                r#"{region}
+        <div id="panel-{n}-magTarget" class="target-mag" style="display: none; height: {th}px; left: {tx}px; overflow: hidden; position: absolute; top: {ty}px; width: {tw}px"><img src="{src}" alt="" style="height: {ih}px; left: -{ix}px; position: absolute; top: -{iy}px; width: {iw}px"/></div>"#
+            )
+        })
+        .collect::<Vec<_>>()
+        .concat()
+}
+
+/// Where the magnification target of `panel` goes on a `width` x `height`
+/// page: its position and size, then the size and offset of the page image
+/// scaled along with it.
+#[expect(
+    clippy::cast_possible_truncation,
+    clippy::cast_sign_loss,
+    reason = "the target lies within the page"
+)]
+fn magnified(panel: Panel, width: u32, height: u32) -> [u32; 8] {
+    let (w, h) = (f64::from(width), f64::from(height));
+    let scale = (1.0 / panel.width).min(1.0 / panel.height);
+    let (tw, th) = (panel.width * w * scale, panel.height * h * scale);
+    [
+        ((w - tw) / 2.0).round() as u32,
+        ((h - th) / 2.0).round() as u32,
+        tw.round() as u32,
+        th.round() as u32,
+        (w * scale).round() as u32,
+        (h * scale).round() as u32,
+        (panel.x * w * scale).round() as u32,
+        (panel.y * h * scale).round() as u32,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::write;
+
+    use super::*;
+    use crate::epub::testing::TestDir;
+
+    #[test]
+    fn panels_json_is_read_by_file() -> Result<()> {
+        let dir = TestDir::new(&[])?;
+        assert!(read_panels_json(&dir.0)?.is_empty());
+
+        write(
+            dir.path(PANELS_FILE),
+            r#"{"a.png": [[0, 0, 40, 30], [0, 30, 40, 30]], "sub/b.png": []}"#,
+        )?;
+        let listed = read_panels_json(&dir.0)?;
+        assert_eq!(listed["a.png"], [[0, 0, 40, 30], [0, 30, 40, 30]]);
+        assert!(listed["sub/b.png"].is_empty());
+
+        write(dir.path(PANELS_FILE), r#"{"a.png": [[0, 0, 40]]}"#)?;
+        assert!(read_panels_json(&dir.0).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn panels_outside_the_image_are_rejected() {
+        assert!(listed_panels(&[[0, 0, 40, 60]], 40, 60).is_ok());
+        assert!(listed_panels(&[[10, 0, 40, 60]], 40, 60).is_err());
+        assert!(listed_panels(&[[0, 30, 40, 31]], 40, 60).is_err());
+        assert!(listed_panels(&[[0, 0, 0, 60]], 40, 60).is_err());
+        assert!(listed_panels(&[[u32::MAX, 0, 1, 1]], 40, 60).is_err());
+    }
+
+    #[test]
+    fn panels_follow_the_image_when_scaled_and_padded() -> Result<()> {
+        let panels = listed_panels(&[[0, 30, 20, 30]], 40, 60)?;
+        assert_eq!(
+            panels[0],
+            Panel {
+                x: 0.0,
+                y: 0.5,
+                width: 0.5,
+                height: 0.5
+            }
+        );
+        // Scaled to 80x120 and centered on a 240x120 page
+        let placed = panels[0].placed((80, 0), (80, 120), (240, 120));
+        assert_eq!(placed.pixels(240, 120), [80, 60, 40, 60]);
+        Ok(())
+    }
+
+    #[test]
+    fn kindle_panels_are_magnified() -> Result<()> {
+        let panels = listed_panels(&[[0, 0, 20, 30]], 40, 60)?;
+        let markup = panel_markup(
+            &panels,
+            (40, 60),
+            "images/000001.webp",
+            EpubVersion::default(),
+            Vendor::Kindle,
+        );
+        assert!(markup.contains(r#"<div id="panel-1" epub:type="panel" style="height: 30px; left: 0px; position: absolute; top: 0px; width: 20px">"#));
+        assert!(markup
+            .contains(r#"data-app-amzn-magnify='{"targetId":"panel-1-magTarget","ordinal":1}'"#));
+        // The top left quarter is zoomed to the whole page
+        assert!(markup.contains(r#"<div id="panel-1-magTarget" class="target-mag" style="display: none; height: 60px; left: 0px; overflow: hidden; position: absolute; top: 0px; width: 40px"><img src="images/000001.webp" alt="" style="height: 120px; left: -0px; position: absolute; top: -0px; width: 80px"/></div>"#));
+        Ok(())
+    }
+
+    #[test]
+    fn other_vendors_only_mark_the_regions() -> Result<()> {
+        let panels = listed_panels(&[[0, 0, 20, 30], [20, 30, 20, 30]], 40, 60)?;
+        let markup = panel_markup(
+            &panels,
+            (40, 60),
+            "images/000001.webp",
+            EpubVersion::default(),
+            Vendor::Kobo,
+        );
+        assert!(markup.contains(r#"<div id="panel-1" epub:type="panel""#));
+        assert!(markup.contains(r#"<div id="panel-2" epub:type="panel" style="height: 30px; left: 20px; position: absolute; top: 30px; width: 20px"></div>"#));
+        assert!(!markup.contains("magnify"));
+        assert!(!markup.contains("magTarget"));
+
+        let markup = panel_markup(
+            &panels,
+            (40, 60),
+            "images/000001.webp",
+            EpubVersion::Epub2,
+            Vendor::Kindle,
+        );
+        assert!(markup.is_empty());
+        Ok(())
+    }
+}
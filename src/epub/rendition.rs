@@ -0,0 +1,297 @@
+use std::str::FromStr;
+
+use anyhow::{bail, Result};
+use serde::Deserialize;
+
+use super::{
+    compat::EpubVersion, generated::GeneratedPage, images::Image, pages::PageSpread, vendor::Vendor,
+};
+
+/// Orientation the book or a page is meant to be read in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Orientation {
+    /// Follows the device
+    #[default]
+    Auto,
+    Portrait,
+    Landscape,
+}
+
+impl FromStr for Orientation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "auto" => Ok(Self::Auto),
+            "portrait" => Ok(Self::Portrait),
+            "landscape" => Ok(Self::Landscape),
+            _ => bail!("invalid orientation: {s} (expected auto, portrait or landscape)"),
+        }
+    }
+}
+
+impl Orientation {
+    /// Value of the `rendition:orientation` property.
+    #[must_use]
+    pub fn value(self) -> &'static str {
+        match self {
+            Self::Auto => "auto",
+            Self::Portrait => "portrait",
+            Self::Landscape => "landscape",
+        }
+    }
+}
+
+/// When two pages are shown side by side as a synthetic spread. The
+/// `portrait` value of EPUB 3 is deprecated and not offered.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Spread {
+    /// Never
+    None,
+    /// Only while the device is in landscape
+    #[default]
+    Landscape,
+    /// In both orientations
+    Both,
+    /// Whenever the reading system sees fit
+    Auto,
+}
+
+impl FromStr for Spread {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" => Ok(Self::None),
+            "landscape" => Ok(Self::Landscape),
+            "both" => Ok(Self::Both),
+            "auto" => Ok(Self::Auto),
+            _ => bail!("invalid spread: {s} (expected none, landscape, both or auto)"),
+        }
+    }
+}
+
+impl Spread {
+    /// Value of the `rendition:spread` property.
+    #[must_use]
+    pub fn value(self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Landscape => "landscape",
+            Self::Both => "both",
+            Self::Auto => "auto",
+        }
+    }
+}
+
+/// Whether a page keeps its fixed layout or reflows like text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Layout {
+    PrePaginated,
+    Reflowable,
+}
+
+impl FromStr for Layout {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "pre-paginated" => Ok(Self::PrePaginated),
+            "reflowable" => Ok(Self::Reflowable),
+            _ => bail!("invalid layout: {s} (expected pre-paginated or reflowable)"),
+        }
+    }
+}
+
+/// Rendition properties of the whole book. The book itself is always
+/// pre-paginated; pages can reflow through [`PageRendition`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct Rendition {
+    pub orientation: Orientation,
+    pub spread: Spread,
+}
+
+impl Rendition {
+    pub fn override_with(&mut self, orientation: Option<Orientation>, spread: Option<Spread>) {
+        if let Some(x) = orientation {
+            self.orientation = x;
+        }
+        if let Some(x) = spread {
+            self.spread = x;
+        }
+    }
+}
+
+/// Rendition properties a page overrides the book's with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PageRendition {
+    pub orientation: Option<Orientation>,
+    pub spread: Option<Spread>,
+    pub layout: Option<Layout>,
+}
+
+impl PageRendition {
+    /// Whether the page reflows, either as a text page or by its own layout.
+    #[must_use]
+    pub fn is_reflowable(self, generated: Option<GeneratedPage>) -> bool {
+        match self.layout {
+            Some(layout) => layout == Layout::Reflowable,
+            None => generated == Some(GeneratedPage::Text),
+        }
+    }
+
+    /// Spine properties overriding the book's, in the order of the
+    /// `rendition:layout`, `rendition:orientation` and `rendition:spread`
+    /// families.
+    #[must_use]
+    pub fn properties(self, generated: Option<GeneratedPage>) -> Vec<String> {
+        [
+            self.is_reflowable(generated)
+                .then(|| "rendition:layout-reflowable".to_string()),
+            self.layout
+                .filter(|x| *x == Layout::PrePaginated)
+                .map(|_| "rendition:layout-pre-paginated".to_string()),
+            self.orientation
+                .map(|x| format!("rendition:orientation-{}", x.value())),
+            self.spread
+                .map(|x| format!("rendition:spread-{}", x.value())),
+        ]
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+}
+
+/// Checks that the rendition properties of every page can be written
+/// together, as EPUB 3 allows a single property of each family per page.
+///
+/// # Errors
+///
+/// Returns an error if:
+/// - A page overrides its rendition properties in an EPUB 2 book.
+/// - A text page is pre-paginated, since it has no viewport.
+/// - A reflowing page is centered on its spread, which only fixed-layout
+///   pages can be.
+/// - A centered page overrides its spread for Kobo, which already shows it
+///   on its own.
+pub fn validate_renditions(images: &[Image], version: EpubVersion, vendor: Vendor) -> Result<()> {
+    for image in images {
+        let name = image.path.display();
+        let rendition = &image.rendition;
+        if *rendition != PageRendition::default() && !version.is_epub3() {
+            bail!("{name}: EPUB 2 cannot override the rendition of a page");
+        }
+        if image.generated == Some(GeneratedPage::Text)
+            && rendition.layout == Some(Layout::PrePaginated)
+        {
+            bail!("{name}: a text page cannot be pre-paginated");
+        }
+        if image.spread == Some(PageSpread::Center) {
+            if rendition.is_reflowable(image.generated) {
+                bail!("{name}: a reflowable page cannot be centered on its spread");
+            }
+            if vendor == Vendor::Kobo && rendition.spread.is_some_and(|x| x != Spread::None) {
+                bail!("{name}: Kobo shows a centered page on its own, whatever its spread");
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text_page(spread: Option<PageSpread>) -> Image {
+        Image {
+            generated: Some(GeneratedPage::Text),
+            spread,
+            ..Image::default()
+        }
+    }
+
+    #[test]
+    fn reflowable_pages_cannot_be_centered() {
+        let centered = text_page(Some(PageSpread::Center));
+        assert!(validate_renditions(&[centered], EpubVersion::Epub3, Vendor::Kindle).is_err());
+        let image = Image {
+            spread: Some(PageSpread::Center),
+            rendition: PageRendition {
+                layout: Some(Layout::Reflowable),
+                ..PageRendition::default()
+            },
+            ..Image::default()
+        };
+        assert!(validate_renditions(&[image], EpubVersion::Epub3, Vendor::Kindle).is_err());
+        assert!(
+            validate_renditions(&[text_page(None)], EpubVersion::Epub3, Vendor::Kindle).is_ok()
+        );
+    }
+
+    #[test]
+    fn overrides_need_epub3() {
+        let page = Image {
+            rendition: PageRendition {
+                orientation: Some(Orientation::Landscape),
+                ..PageRendition::default()
+            },
+            ..Image::default()
+        };
+        let pages = [page];
+        assert!(validate_renditions(&pages, EpubVersion::Epub2, Vendor::Kindle).is_err());
+        assert!(validate_renditions(&pages, EpubVersion::Compatible, Vendor::Kindle).is_ok());
+        assert!(validate_renditions(&pages, EpubVersion::Epub3, Vendor::Kindle).is_ok());
+    }
+
+    #[test]
+    fn text_pages_cannot_be_pre_paginated() {
+        let page = Image {
+            rendition: PageRendition {
+                layout: Some(Layout::PrePaginated),
+                ..PageRendition::default()
+            },
+            ..text_page(None)
+        };
+        assert!(validate_renditions(&[page], EpubVersion::Epub3, Vendor::Kindle).is_err());
+    }
+
+    #[test]
+    fn centered_pages_keep_their_spread_off_kobo() {
+        let page = Image {
+            spread: Some(PageSpread::Center),
+            rendition: PageRendition {
+                spread: Some(Spread::Both),
+                ..PageRendition::default()
+            },
+            ..Image::default()
+        };
+        let pages = [page];
+        assert!(validate_renditions(&pages, EpubVersion::Epub3, Vendor::Kindle).is_ok());
+        assert!(validate_renditions(&pages, EpubVersion::Epub3, Vendor::Kobo).is_err());
+    }
+
+    #[test]
+    fn overrides_are_written_one_per_family() {
+        let rendition = PageRendition {
+            orientation: Some(Orientation::Portrait),
+            spread: Some(Spread::None),
+            layout: Some(Layout::PrePaginated),
+        };
+        assert_eq!(
+            rendition.properties(None),
+            [
+                "rendition:layout-pre-paginated",
+                "rendition:orientation-portrait",
+                "rendition:spread-none"
+            ]
+        );
+        assert_eq!(
+            PageRendition::default().properties(Some(GeneratedPage::Text)),
+            ["rendition:layout-reflowable"]
+        );
+    }
+}
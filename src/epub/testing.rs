@@ -0,0 +1,39 @@
+use std::{
+    fs::{create_dir_all, remove_dir_all},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use image::{Rgb, RgbImage};
+use uuid::Uuid;
+
+/// An image directory under `target`, named as `./target/...` the way the
+/// Taskfile names its input, and removed when dropped.
+pub struct TestDir(pub String);
+
+impl TestDir {
+    /// Creates the directory with a small image of its own color for each
+    /// of `images`.
+    pub fn new(images: &[&str]) -> Result<Self> {
+        let dir = Self(format!("./target/test-{}", Uuid::new_v4()));
+        create_dir_all(&dir.0)?;
+        for (i, name) in (0u8..).zip(images) {
+            let path = dir.path(name);
+            if let Some(parent) = path.parent() {
+                create_dir_all(parent)?;
+            }
+            RgbImage::from_pixel(40, 60, Rgb([i.wrapping_mul(40), 100, 200])).save(path)?;
+        }
+        Ok(dir)
+    }
+
+    pub fn path(&self, name: &str) -> PathBuf {
+        Path::new(&self.0).join(name)
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = remove_dir_all(&self.0);
+    }
+}
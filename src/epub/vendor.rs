@@ -0,0 +1,229 @@
+use std::{fs::write, path::Path, str::FromStr};
+
+use anyhow::{bail, Result};
+
+use super::{
+    converter::Metadata,
+    rendition::{Orientation, Rendition},
+};
+
+/// Path of the display options of Apple Books, relative to the EPUB directory.
+const APPLE_DISPLAY_OPTIONS: &str = "META-INF/com.apple.ibooks.display-options.xml";
+
+/// Which reading app the book is tuned for, beyond what EPUB itself says.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Vendor {
+    /// Kindle, through the `<meta name="...">` entries of Kindle Comic Creator
+    #[default]
+    Kindle,
+    /// Apple Books, through its display options file
+    Apple,
+    /// Kobo, which does not center pages on a spread
+    Kobo,
+}
+
+impl FromStr for Vendor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "kindle" => Ok(Self::Kindle),
+            "apple" => Ok(Self::Apple),
+            "kobo" => Ok(Self::Kobo),
+            _ => bail!("invalid vendor: {s} (expected kindle, apple or kobo)"),
+        }
+    }
+}
+
+impl Vendor {
+    /// Detects the vendor a book in `dir` was written for from its files and
+    /// its package document `opf`.
+    #[must_use]
+    pub fn detect(dir: &Path, opf: &str) -> Self {
+        if dir.join(APPLE_DISPLAY_OPTIONS).exists() {
+            Self::Apple
+        } else if opf.contains(r#"<meta name="book-type" content="comic"/>"#) {
+            Self::Kindle
+        } else {
+            Self::Kobo
+        }
+    }
+
+    /// Writes the files the vendor reads besides the package, if any.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if writing a file fails.
+    pub fn write_files(self, dir: &str, rendition: Rendition) -> Result<()> {
+        if self == Self::Apple {
+            let orientation_lock = match rendition.orientation {
+                Orientation::Auto => "none",
+                Orientation::Portrait => "portrait-only",
+                Orientation::Landscape => "landscape-only",
+            };
+            write(
+                format!("{dir}/{APPLE_DISPLAY_OPTIONS}"),
+                format!(
+                    r#"<?xml version="1.0" encoding="UTF-8" ?>
+<display_options>
+    <platform name="*">
+        <option name="fixed-layout">true</option>
+        <option name="orientation-lock">{orientation_lock}</option>
+        <option name="specified-fonts">true</option>
+    </platform>
+</display_options>"#
+                ),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Metadata of the package only the vendor reads. The original resolution
+    /// is kept for every vendor since editing a book reads the canvas back
+    /// from it, and right-to-left books keep the writing mode in EPUB 2,
+    /// which has no page progression of its own. Kindle turns on region
+    /// magnification if pages have `panels`.
+    #[must_use]
+    pub fn meta_tags(
+        self,
+        (max_width, max_height): (u32, u32),
+        metadata: &Metadata,
+        epub3: bool,
+        panels: bool,
+    ) -> String {
+        let kindle = if self == Self::Kindle {
+            format!(
+                r#"
+        <meta name="fixed-layout" content="true"/>
+        <meta name="book-type" content="comic"/>
+        <meta name="orientation-lock" content="{}"/>{}"#,
+                metadata.rendition.orientation.value(),
+                if panels && epub3 {
+                    r#"
+        <meta name="RegionMagnification" content="true"/>"#
+                } else {
+                    ""
+                }
+            )
+        } else {
+            String::new()
+        };
+        let rtl_meta = if metadata.is_rtl && (self == Self::Kindle || !epub3) {
+            r#"
+        <meta name="primary-writing-mode" content="horizontal-rl"/>"#
+        } else {
+            ""
+        };
+        format!(
+            r#"{kindle}
+        <meta name="original-resolution" content="{max_width}x{max_height}"/>{rtl_meta}"#
+        )
+    }
+
+    /// Spine property of a page centered on its spread. Kobo pairs such a
+    /// page with its neighbour anyway, so it is shown on its own instead.
+    #[must_use]
+    pub fn center_property(self) -> &'static str {
+        if self == Self::Kobo {
+            "rendition:spread-none"
+        } else {
+            "rendition:page-spread-center"
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir_all, read_to_string};
+
+    use super::*;
+    use crate::epub::testing::TestDir;
+
+    fn metadata(is_rtl: bool, orientation: &str) -> Result<Metadata> {
+        Ok(serde_json::from_str(&format!(
+            r#"{{"title": "Test", "is_rtl": {is_rtl}, "rendition": {{"orientation": "{orientation}"}}}}"#
+        ))?)
+    }
+
+    #[test]
+    fn kindle_reads_comic_meta() -> Result<()> {
+        let metadata = metadata(true, "portrait")?;
+        let tags = Vendor::Kindle.meta_tags((40, 60), &metadata, true, true);
+        for tag in [
+            r#"<meta name="fixed-layout" content="true"/>"#,
+            r#"<meta name="book-type" content="comic"/>"#,
+            r#"<meta name="orientation-lock" content="portrait"/>"#,
+            r#"<meta name="RegionMagnification" content="true"/>"#,
+            r#"<meta name="original-resolution" content="40x60"/>"#,
+            r#"<meta name="primary-writing-mode" content="horizontal-rl"/>"#,
+        ] {
+            assert!(tags.contains(tag), "{tag}");
+        }
+        // Region magnification needs panels and EPUB 3
+        let tags = Vendor::Kindle.meta_tags((40, 60), &metadata, true, false);
+        assert!(!tags.contains("RegionMagnification"));
+        let tags = Vendor::Kindle.meta_tags((40, 60), &metadata, false, true);
+        assert!(!tags.contains("RegionMagnification"));
+
+        let dir = TestDir::new(&[])?;
+        assert_eq!(
+            Vendor::detect(Path::new(&dir.0), &format!("<metadata>{tags}</metadata>")),
+            Vendor::Kindle
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn apple_reads_its_display_options() -> Result<()> {
+        let dir = TestDir::new(&[])?;
+        create_dir_all(dir.path("META-INF"))?;
+        Vendor::Apple.write_files(&dir.0, metadata(false, "landscape")?.rendition)?;
+        assert_eq!(
+            read_to_string(dir.path(APPLE_DISPLAY_OPTIONS))?,
+            r#"<?xml version="1.0" encoding="UTF-8" ?>
+<display_options>
+    <platform name="*">
+        <option name="fixed-layout">true</option>
+        <option name="orientation-lock">landscape-only</option>
+        <option name="specified-fonts">true</option>
+    </platform>
+</display_options>"#
+        );
+        assert_eq!(Vendor::detect(Path::new(&dir.0), ""), Vendor::Apple);
+
+        let tags = Vendor::Apple.meta_tags((40, 60), &metadata(true, "auto")?, true, true);
+        assert!(!tags.contains("book-type"));
+        assert!(!tags.contains("primary-writing-mode"));
+        Ok(())
+    }
+
+    #[test]
+    fn kobo_shows_centered_pages_on_their_own() -> Result<()> {
+        assert_eq!(Vendor::Kobo.center_property(), "rendition:spread-none");
+        assert_eq!(
+            Vendor::Kindle.center_property(),
+            "rendition:page-spread-center"
+        );
+        assert_eq!(
+            Vendor::Apple.center_property(),
+            "rendition:page-spread-center"
+        );
+
+        let dir = TestDir::new(&[])?;
+        create_dir_all(dir.path("META-INF"))?;
+        Vendor::Kobo.write_files(&dir.0, Rendition::default())?;
+        assert!(!dir.path(APPLE_DISPLAY_OPTIONS).exists());
+        let metadata = metadata(true, "auto")?;
+        let tags = Vendor::Kobo.meta_tags((40, 60), &metadata, true, false);
+        assert_eq!(
+            tags,
+            r#"
+        <meta name="original-resolution" content="40x60"/>"#
+        );
+        assert_eq!(Vendor::detect(Path::new(&dir.0), &tags), Vendor::Kobo);
+        // EPUB 2 has no page progression, so the writing mode says it
+        let tags = Vendor::Kobo.meta_tags((40, 60), &metadata, false, false);
+        assert!(tags.contains(r#"<meta name="primary-writing-mode" content="horizontal-rl"/>"#));
+        Ok(())
+    }
+}
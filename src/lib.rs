@@ -1,16 +1,42 @@
 mod epub;
 
 use std::path::PathBuf;
-use std::{fs::File, io::BufReader, path::Path};
+use std::{
+    fs::{create_dir_all, read_dir, File},
+    io::BufReader,
+    path::Path,
+    time::Duration,
+};
 
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Result};
 use chrono::Utc;
+use epub::accessibility::{read_alt_text_json, Accessibility};
+use epub::cache::PageCache;
+pub use epub::cache::PruneSummary;
+use epub::compat::create_ncx_file;
+pub use epub::compat::EpubVersion;
 pub use epub::converter::get_metadata;
 use epub::converter::{
-    create_nav_file, create_opf_file, create_part_files, initialize_directory, rm_directory,
-    zip_epub, Metadata, OpfParams,
+    create_nav_file, create_opf_file, create_part_files, initialize_directory, landmarks,
+    rm_directory, zip_epub, Chapter, Metadata, OpfParams,
+};
+pub use epub::detection::{detect_panels, write_panel_overlay};
+use epub::editor::read_generated_book;
+pub use epub::editor::{set_metadata, MetadataUpdate};
+use epub::generated::{cover_svg, GeneratedPage};
+use epub::images::{
+    contains_image_files, open_image_file, padding_image_file, sort_image_files, Image, PageFormat,
+};
+pub use epub::images::{
+    Color, Padding, PageOptions, ResizeFilter, ScanReport, SortStrategy, Unsharp,
 };
-use epub::images::{padding_image_file, sort_image_files};
+pub use epub::pages::PageNumbering;
+use epub::pages::{read_pages_txt, PageEntry, PageSpread};
+pub use epub::panels::Panel;
+use epub::panels::{listed_panels, read_panels_json};
+use epub::rendition::{validate_renditions, Rendition};
+pub use epub::rendition::{Orientation, Spread};
+pub use epub::vendor::Vendor;
 use serde_json::from_reader;
 use uuid::Uuid;
 
@@ -19,6 +45,11 @@ pub enum Direction {
     RTL,
 }
 
+#[derive(Clone)]
+#[expect(
+    clippy::struct_excessive_bools,
+    reason = "the flags are independent options of the conversion"
+)]
 pub struct EpubOptions {
     pub image_dir: String,
     pub out: String,
@@ -28,6 +59,62 @@ pub struct EpubOptions {
     pub publication_date: Option<String>,
     pub is_rtl: Option<bool>,
     pub blank: Option<bool>,
+    /// Directory to cache processed pages in, so that unchanged pages are
+    /// reused by later builds
+    pub cache_dir: Option<String>,
+    /// How the pages are ordered
+    pub sort: SortStrategy,
+    /// Leave out images that cannot be decoded instead of failing
+    pub skip_bad: bool,
+    /// How each page is turned into an image of the book
+    pub page_options: PageOptions,
+    /// Size in bytes each EPUB must fit in; pages are shrunk further until
+    /// it does
+    pub max_size: Option<u64>,
+    /// Image used as the cover instead of the first page, overriding the
+    /// `cover` in metadata.json
+    pub cover: Option<String>,
+    /// Show the cover as a page of its own before the reading order.
+    /// Without it, the cover is only the cover image of the book and the
+    /// first page stays in the reading order.
+    pub cover_page: bool,
+    /// Draw a cover with the title, series and creator instead of using an
+    /// image; ignored if `cover` is given
+    pub generate_cover: bool,
+    /// Add a title page listing the metadata after the cover
+    pub title_page: bool,
+    /// Number the pages without a label of their own in the page list
+    pub page_numbering: Option<PageNumbering>,
+    /// Which reading systems the EPUB is written for
+    pub version: EpubVersion,
+    /// Which reading app the EPUB is tuned for
+    pub vendor: Vendor,
+    /// Orientation the book is read in, overriding the `rendition` in
+    /// metadata.json
+    pub orientation: Option<Orientation>,
+    /// When pages are shown as spreads, overriding the `rendition` in
+    /// metadata.json
+    pub spread: Option<Spread>,
+    /// Find the panels of pages not listed in panels.json by their gutters
+    pub detect_panels: bool,
+}
+
+/// An EPUB written by a conversion.
+pub struct BuiltBook {
+    pub path: String,
+    /// Size of the file in bytes
+    pub size: u64,
+    /// Options the pages were finally processed with, which lower the
+    /// quality and limit the pixels per page further than the given ones if
+    /// that was needed to fit `max_size`
+    pub page_options: PageOptions,
+}
+
+/// Result of [`img2epub`] and [`split_volumes`].
+pub struct ConversionReport {
+    /// Files that did not become pages
+    pub files: ScanReport,
+    pub books: Vec<BuiltBook>,
 }
 
 /// # Errors
@@ -36,8 +123,12 @@ pub struct EpubOptions {
 /// - The metadata.json file exists but cannot be parsed.
 /// - No image files are found.
 /// - Title is not provided and there is no metadata.json.
+/// - An image cannot be decoded and `skip_bad` is not set.
+/// - The cover would be a text page.
+/// - The rendition properties of a page cannot be written together.
+/// - The book does not fit in `max_size`.
 /// - Any file I/O operation fails.
-pub fn img2epub(opts: EpubOptions) -> Result<()> {
+pub fn img2epub(opts: EpubOptions) -> Result<ConversionReport> {
     let EpubOptions {
         image_dir,
         out,
@@ -47,107 +138,1636 @@ pub fn img2epub(opts: EpubOptions) -> Result<()> {
         publication_date,
         is_rtl,
         blank,
+        cache_dir,
+        sort,
+        skip_bad,
+        page_options,
+        max_size,
+        cover,
+        cover_page,
+        generate_cover,
+        title_page,
+        page_numbering,
+        version,
+        vendor,
+        orientation,
+        spread,
+        detect_panels,
     } = opts;
 
     // Create metadata
-    let json_path = format!("{image_dir}/metadata.json");
-    let metadata: Metadata = if Path::new(&json_path).exists() {
-        let buf = BufReader::new(File::open(&json_path)?);
-        let mut meta: Metadata = from_reader(buf)?;
+    let mut metadata = load_metadata(
+        &image_dir,
+        title,
+        creator,
+        publisher,
+        publication_date,
+        is_rtl,
+        blank,
+    )?;
+    metadata.rendition.override_with(orientation, spread);
+
+    // Sort image files by name, unless the order is given explicitly
+    let order = page_order(&image_dir, &metadata)?;
+    let (sorted_files, files) = sort_image_files(&image_dir, sort, order.as_deref(), skip_bad)?;
+    let sorted_files = with_panels(&image_dir, sorted_files, detect_panels, metadata.is_rtl)?;
+    let sorted_files = with_alt_text(&image_dir, sorted_files)?;
+    // A cover image of its own is fitted to the canvas of the pages rather
+    // than counted in it
+    let book_canvas = |options: &PageOptions| canvas_size(&scale_pages(&sorted_files, options));
+    let pages = with_cover(
+        sorted_files.clone(),
+        cover_path(&image_dir, cover, &metadata).as_deref(),
+        generate_cover,
+        cover_page,
+    )?;
+    let pages = with_title_page(pages, title_page);
+
+    let cache = cache_dir.as_deref().map(PageCache::new).transpose()?;
+    let identifier = format!("urn:uuid:{}", Uuid::new_v4());
+
+    let book = build_within_size(&sorted_files, &page_options, max_size, &out, |options| {
+        // Get the maximum width and height of the images
+        let (max_width, max_height) = book_canvas(options)?;
+
+        build_epub(
+            scale_pages(&pages, options),
+            &metadata,
+            &BookParams {
+                identifier: &identifier,
+                language: "ja-JP",
+                max_width,
+                max_height,
+                chapters: &Chapter::cover(),
+                volumes: &[],
+                cache: cache.as_ref(),
+                page_options: options,
+                cover_page,
+                page_numbering,
+                version,
+                vendor,
+            },
+            &out,
+        )
+    })?;
+
+    Ok(ConversionReport {
+        files,
+        books: vec![book],
+    })
+}
+
+/// How [`split_volumes`] divides the pages of a book.
+pub enum SplitMode {
+    /// At most this many pages per volume
+    Pages(usize),
+    /// At most this many bytes of source images per volume. The volumes
+    /// themselves differ in size, as their pages are encoded anew; use
+    /// `max_size` to limit that.
+    SourceSize(u64),
+    /// One volume per subdirectory of the image directory
+    Chapters,
+}
+
+/// Converts an image directory into several volume EPUBs.
+///
+/// `opts.out` is the directory the volumes are written to, named
+/// `{title} Vol. {n}.epub`. Every volume is tagged as part of a series named
+/// after the book (or the `series` in metadata.json) and uses its own first
+/// page as the cover, except that the cover given, if any, is used for the
+/// first volume. `max_size` applies to each volume.
+///
+/// # Errors
+///
+/// Returns an error if:
+/// - The metadata.json file exists but cannot be parsed.
+/// - No image files are found.
+/// - Title is not provided and there is no metadata.json.
+/// - An image cannot be decoded and `skip_bad` is not set.
+/// - The cover of a volume would be a text page.
+/// - The rendition properties of a page cannot be written together.
+/// - A volume does not fit in `max_size`.
+/// - Any file I/O operation fails.
+pub fn split_volumes(opts: EpubOptions, mode: &SplitMode) -> Result<ConversionReport> {
+    let EpubOptions {
+        image_dir,
+        out: out_dir,
+        title,
+        creator,
+        publisher,
+        publication_date,
+        is_rtl,
+        blank,
+        cache_dir,
+        sort,
+        skip_bad,
+        page_options,
+        max_size,
+        cover,
+        cover_page,
+        generate_cover,
+        title_page,
+        page_numbering,
+        version,
+        vendor,
+        orientation,
+        spread,
+        detect_panels,
+    } = opts;
+
+    let mut metadata = load_metadata(
+        &image_dir,
+        title,
+        creator,
+        publisher,
+        publication_date,
+        is_rtl,
+        blank,
+    )?;
+    metadata.rendition.override_with(orientation, spread);
+    let order = page_order(&image_dir, &metadata)?;
+    let (sorted_files, files) = sort_image_files(&image_dir, sort, order.as_deref(), skip_bad)?;
+    let sorted_files = with_panels(&image_dir, sorted_files, detect_panels, metadata.is_rtl)?;
+    let sorted_files = with_alt_text(&image_dir, sorted_files)?;
+    // Every volume shares the canvas of the whole book
+    let book_canvas = |options: &PageOptions| canvas_size(&scale_pages(&sorted_files, options));
+    book_canvas(&page_options)?;
+    let cache = cache_dir.as_deref().map(PageCache::new).transpose()?;
+
+    let parts = split_pages(&sorted_files, mode)?;
+
+    let series = metadata
+        .series
+        .clone()
+        .unwrap_or_else(|| metadata.title.clone());
+    let cover = cover_path(&image_dir, cover, &metadata);
+    create_dir_all(&out_dir)?;
+    let mut books = Vec::new();
+    for (i, part) in parts.into_iter().enumerate() {
+        let volume = u32::try_from(i + 1)?;
+        let part = with_cover(
+            part,
+            cover.as_deref().filter(|_| i == 0),
+            generate_cover,
+            cover_page,
+        )?;
+        let part = with_title_page(part, title_page);
+        let title = format!("{} Vol. {volume}", metadata.title);
+        let out = volume_path(&out_dir, &title);
+        let metadata = Metadata {
+            title,
+            series: Some(series.clone()),
+            volume: Some(volume),
+            ..metadata.clone()
+        };
+        let identifier = format!("urn:uuid:{}", Uuid::new_v4());
+        books.push(build_within_size(
+            &part,
+            &page_options,
+            max_size,
+            &out,
+            |options| {
+                let (max_width, max_height) = book_canvas(options)?;
+                build_epub(
+                    scale_pages(&part, options),
+                    &metadata,
+                    &BookParams {
+                        identifier: &identifier,
+                        language: "ja-JP",
+                        max_width,
+                        max_height,
+                        chapters: &Chapter::cover(),
+                        volumes: &[],
+                        cache: cache.as_ref(),
+                        page_options: options,
+                        cover_page,
+                        page_numbering,
+                        version,
+                        vendor,
+                    },
+                    &out,
+                )
+            },
+        )?);
+    }
+
+    Ok(ConversionReport { files, books })
+}
+
+/// Path of the volume titled `title` in `out_dir`.
+fn volume_path(out_dir: &str, title: &str) -> String {
+    Path::new(out_dir)
+        .join(format!("{}.epub", title.replace('/', "_")))
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Divides the pages into volumes as `mode` says.
+fn split_pages(files: &[Image], mode: &SplitMode) -> Result<Vec<Vec<Image>>> {
+    if matches!(mode, SplitMode::Pages(0) | SplitMode::SourceSize(0)) {
+        bail!("volume limit must be greater than 0");
+    }
+
+    let mut parts: Vec<Vec<Image>> = Vec::new();
+    let mut part_size = 0;
+    for file in files {
+        let size = std::fs::metadata(&file.path)?.len();
+        let starts_new_part = match (parts.last(), mode) {
+            (None, _) => true,
+            (Some(part), SplitMode::Pages(n)) => part.len() >= *n,
+            (Some(_), SplitMode::SourceSize(limit)) => part_size + size > *limit,
+            (Some(part), SplitMode::Chapters) => {
+                part.last().and_then(|x| x.path.parent()) != file.path.parent()
+            }
+        };
+        if starts_new_part {
+            parts.push(Vec::new());
+            part_size = 0;
+        }
+        part_size += size;
+        if let Some(part) = parts.last_mut() {
+            part.push(file.clone());
+        }
+    }
+
+    Ok(parts)
+}
+
+/// Removes processed pages from a cache directory given as `cache_dir` to
+/// [`EpubOptions`]: first the ones not used for longer than `max_age`, then
+/// the least recently used ones until the cache is no larger than `max_size`.
+/// Everything is removed if neither limit is given. Only files named like
+/// processed pages are removed.
+///
+/// # Errors
+///
+/// Returns an error if the cache directory does not exist or cannot be read,
+/// or an entry cannot be removed.
+pub fn prune_cache(
+    cache_dir: &str,
+    max_age: Option<Duration>,
+    max_size: Option<u64>,
+) -> Result<PruneSummary> {
+    PageCache::open(cache_dir)?.prune(max_age, max_size)
+}
+
+/// Lists the book folders of a library directory, i.e. its subdirectories
+/// that contain image files or a metadata.json, sorted by name.
+///
+/// # Errors
+///
+/// Returns an error if the library directory cannot be read.
+pub fn find_book_dirs(library_dir: &str) -> Result<Vec<String>> {
+    let mut dirs = Vec::new();
+    for entry in read_dir(library_dir)? {
+        let path = entry?.path();
+        if !path.is_dir() {
+            continue;
+        }
+        let dir = path.to_string_lossy().into_owned();
+        if path.join("metadata.json").exists() || contains_image_files(&dir)? {
+            dirs.push(dir);
+        }
+    }
+    dirs.sort();
+    Ok(dirs)
+}
+
+/// Builds the metadata of a book from metadata.json and the options overriding it.
+fn load_metadata(
+    image_dir: &str,
+    title: Option<String>,
+    creator: Option<String>,
+    publisher: Option<String>,
+    publication_date: Option<String>,
+    is_rtl: Option<bool>,
+    blank: Option<bool>,
+) -> Result<Metadata> {
+    if let Some(mut meta) = read_metadata(image_dir)? {
         meta.override_with(title, creator, publisher, publication_date, is_rtl);
-        meta
-    } else if let Some(ref t) = title {
-        Metadata {
-            title: t.clone(),
+        Ok(meta)
+    } else if let Some(t) = title {
+        Ok(Metadata {
+            title: t,
             creator,
             publisher,
             date: publication_date,
             is_rtl: is_rtl.unwrap_or(false),
             blank,
-        }
+            series: None,
+            volume: None,
+            pages: None,
+            cover: None,
+            rendition: Rendition::default(),
+            accessibility: Accessibility::default(),
+        })
     } else {
         bail!("title is required");
+    }
+}
+
+/// Returns the explicit reading order of the image directory, from either
+/// `pages.txt` or the `pages` array of metadata.json.
+fn page_order(image_dir: &str, metadata: &Metadata) -> Result<Option<Vec<PageEntry>>> {
+    match (read_pages_txt(image_dir)?, &metadata.pages) {
+        (Some(_), Some(_)) => {
+            bail!("page order is given by both pages.txt and metadata.json in {image_dir}")
+        }
+        (Some(order), None) => Ok(Some(order)),
+        (None, order) => Ok(order.clone()),
+    }
+}
+
+/// Returns the path of the cover image given as an option, or else in
+/// metadata.json relative to the image directory.
+fn cover_path(image_dir: &str, cover: Option<String>, metadata: &Metadata) -> Option<PathBuf> {
+    cover.map(PathBuf::from).or_else(|| {
+        metadata
+            .cover
+            .as_ref()
+            .map(|x| Path::new(image_dir).join(x))
+    })
+}
+
+/// Puts the cover in front of the pages: the image at `cover`, a generated
+/// one, or a copy of the first page if the cover is not shown as a page of
+/// its own. Otherwise the first page itself is the cover.
+fn with_cover(
+    mut pages: Vec<Image>,
+    cover: Option<&Path>,
+    generate: bool,
+    cover_page: bool,
+) -> Result<Vec<Image>> {
+    let cover = match (cover, pages.first()) {
+        (Some(path), _) => Some(
+            open_image_file(path, "cover".to_string())
+                .map_err(|e| anyhow!("cover {}: {e}", path.display()))?,
+        ),
+        (None, _) if generate => Some(Image {
+            file_name: "cover".to_string(),
+            generated: Some(GeneratedPage::Cover),
+            ..Image::default()
+        }),
+        (None, Some(first)) if !cover_page => Some(Image {
+            spread: None,
+            label: None,
+            ..first.clone()
+        }),
+        _ => None,
     };
+    if let Some(cover) = cover {
+        pages.insert(0, cover);
+    }
+    if let Some(first) = pages.first().filter(|x| !x.has_image()) {
+        bail!("{}: a text page cannot be the cover", first.path.display());
+    }
+    Ok(pages)
+}
 
-    let epub_dir = format!("/tmp/epub-{}", Uuid::new_v4());
-    initialize_directory(&epub_dir)?;
+/// Gives the pages the panels listed in panels.json, and finds the panels of
+/// the other image pages if `detect` is set.
+fn with_panels(
+    image_dir: &str,
+    mut pages: Vec<Image>,
+    detect: bool,
+    is_rtl: bool,
+) -> Result<Vec<Image>> {
+    let listed = read_panels_json(image_dir)?;
+    for page in &mut pages {
+        if page.generated.is_some() {
+            continue;
+        }
+        page.panels = match page.source.as_ref().and_then(|x| listed.get(x)) {
+            Some(rects) => listed_panels(rects, page.width, page.height)
+                .map_err(|e| anyhow!("{}: {e}", page.path.display()))?,
+            None if detect => detect_panels(&page.path, is_rtl)
+                .map_err(|e| anyhow!("{}: {e}", page.path.display()))?,
+            None => Vec::new(),
+        };
+    }
+    Ok(pages)
+}
+
+/// Gives the pages the text descriptions listed in alt-text.json, which
+/// may only list files of the image directory.
+fn with_alt_text(image_dir: &str, mut pages: Vec<Image>) -> Result<Vec<Image>> {
+    let listed = read_alt_text_json(image_dir)?;
+    let mut missing = listed
+        .keys()
+        .filter(|x| !Path::new(image_dir).join(x).is_file())
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        missing.sort_unstable();
+        bail!(
+            "alt-text.json lists files not found in {image_dir}: {}",
+            missing.join(", ")
+        );
+    }
+    for page in &mut pages {
+        if page.generated.is_none() {
+            page.alt = page.source.as_ref().and_then(|x| listed.get(x)).cloned();
+        }
+    }
+    Ok(pages)
+}
+
+/// Puts a generated title page right after the cover, if `title_page` is set.
+fn with_title_page(mut pages: Vec<Image>, title_page: bool) -> Vec<Image> {
+    if title_page && !pages.is_empty() {
+        pages.insert(
+            1,
+            Image {
+                file_name: "title".to_string(),
+                generated: Some(GeneratedPage::TitlePage),
+                ..Image::default()
+            },
+        );
+    }
+    pages
+}
+
+/// Returns the pages with the size they are shrunk to by `options`.
+fn scale_pages(files: &[Image], options: &PageOptions) -> Vec<Image> {
+    files
+        .iter()
+        .map(|x| {
+            let (width, height) = options.scaled_size(x.width, x.height);
+            Image {
+                width,
+                height,
+                ..x.clone()
+            }
+        })
+        .collect()
+}
+
+/// Builds a book with `build`, then rebuilds it until the file at `out` is
+/// no larger than `max_size`: first with the pages encoded as JPEG at lower
+/// and lower qualities, then with fewer pixels per page. If the book does
+/// not fit even so, no file is left at `out`.
+///
+/// The size of a page is taken to grow with its number of pixels, so each
+/// attempt shrinks the pages by how far the previous one was over budget.
+fn build_within_size(
+    files: &[Image],
+    page_options: &PageOptions,
+    max_size: Option<u64>,
+    out: &str,
+    build: impl FnMut(&PageOptions) -> Result<()>,
+) -> Result<BuiltBook> {
+    let result = fit_within_size(files, page_options, max_size, out, build);
+    if result.is_err() && max_size.is_some() && Path::new(out).exists() {
+        std::fs::remove_file(out)?;
+    }
+    result
+}
+
+#[expect(
+    clippy::cast_possible_truncation,
+    clippy::cast_precision_loss,
+    clippy::cast_sign_loss,
+    reason = "the pixel count only needs to be approximate"
+)]
+fn fit_within_size(
+    files: &[Image],
+    page_options: &PageOptions,
+    max_size: Option<u64>,
+    out: &str,
+    mut build: impl FnMut(&PageOptions) -> Result<()>,
+) -> Result<BuiltBook> {
+    // JPEG qualities tried before the pages are shrunk
+    const QUALITIES: [u8; 3] = [80, 65, 50];
+    // Give up after this many rebuilds with fewer pixels
+    const MAX_ATTEMPTS: usize = 8;
+    // Aim a little below the budget, as the estimate is rough
+    const MARGIN: f64 = 0.95;
 
-    // Sort image files by name
-    let mut sorted_files = sort_image_files(&image_dir)?;
+    let mut options = page_options.clone();
+    build(&options)?;
+    let mut size = std::fs::metadata(out)?.len();
+
+    let mut pixels = scale_pages(files, &options)
+        .iter()
+        .map(|x| u64::from(x.width) * u64::from(x.height))
+        .max()
+        .unwrap_or(0);
+    let mut qualities = QUALITIES
+        .into_iter()
+        .filter(|&x| page_options.quality.is_none_or(|quality| x < quality));
+    let mut attempts = 0;
+    while let Some(max_size) = max_size.filter(|&x| size > x) {
+        if let Some(quality) = qualities.next() {
+            options.quality = Some(quality);
+        } else {
+            if attempts == MAX_ATTEMPTS || pixels <= 1 {
+                bail!("{out} would be {size} bytes, over the limit of {max_size}, even with {pixels} pixels per page");
+            }
+            pixels = ((pixels as f64 * max_size as f64 / size as f64 * MARGIN) as u64).max(1);
+            options.max_pixels = Some(pixels);
+            attempts += 1;
+        }
+        build(&options)?;
+        size = std::fs::metadata(out)?.len();
+    }
+
+    Ok(BuiltBook {
+        path: out.to_string(),
+        size,
+        page_options: options,
+    })
+}
 
-    // Get the maximum width and height of the images
-    let sizes = sorted_files.iter().map(|x| (x.width, x.height));
+/// Returns the size of the canvas every page is padded to. Generated pages
+/// take the size of the canvas.
+fn canvas_size(files: &[Image]) -> Result<(u32, u32)> {
+    let sizes = files
+        .iter()
+        .filter(|x| x.generated.is_none())
+        .map(|x| (x.width, x.height));
     if sizes.clone().count() == 0 {
         bail!("No image files found");
     }
     let max_width: u32 = sizes.clone().map(|s| s.0).max().unwrap_or(0);
     let max_height = sizes.clone().map(|s| s.1).max().unwrap_or(0);
+    Ok((max_width, max_height))
+}
+
+/// Reads `metadata.json` in the image directory, if there is one.
+fn read_metadata(image_dir: &str) -> Result<Option<Metadata>> {
+    let json_path = format!("{image_dir}/metadata.json");
+    if !Path::new(&json_path).exists() {
+        return Ok(None);
+    }
+    let buf = BufReader::new(File::open(&json_path)?);
+    Ok(Some(from_reader(buf)?))
+}
+
+struct BookParams<'a> {
+    identifier: &'a str,
+    language: &'a str,
+    max_width: u32,
+    max_height: u32,
+    chapters: &'a [Chapter],
+    volumes: &'a [Metadata],
+    cache: Option<&'a PageCache>,
+    page_options: &'a PageOptions,
+    /// Whether the cover is shown as a page of its own
+    cover_page: bool,
+    page_numbering: Option<PageNumbering>,
+    version: EpubVersion,
+    vendor: Vendor,
+}
+
+/// Writes `pages` padded to the canvas, or at their own size if the page
+/// options say so, as an EPUB at `out`. The first page is used as the cover.
+fn build_epub(
+    mut sorted_files: Vec<Image>,
+    metadata: &Metadata,
+    params: &BookParams<'_>,
+    out: &str,
+) -> Result<()> {
+    let BookParams {
+        max_width,
+        max_height,
+        chapters,
+        cache,
+        page_options,
+        cover_page,
+        page_numbering,
+        version,
+        vendor,
+        ..
+    } = *params;
+    let format = page_options.page_format(version);
+
+    let epub_dir = format!("/tmp/epub-{}", Uuid::new_v4());
+    initialize_directory(&epub_dir, vendor, metadata.rendition)?;
 
     // Create blank page
-    if metadata.blank.is_some_and(|x| x) {
-        let blank_page = format!("{epub_dir}/OEBPS/images/blank.webp");
-        let mut imgbuf: image::ImageBuffer<image::Rgb<u8>, Vec<u8>> =
-            image::ImageBuffer::new(max_width, max_height);
-        for x in 0..max_width {
-            for y in 0..max_height {
-                imgbuf.put_pixel(x, y, image::Rgb([255, 255, 255]));
-            }
-        }
-        imgbuf.save(&blank_page)?;
+    let blank = metadata.blank.is_some_and(|x| x);
+    if blank {
+        let blank_page = format!("{epub_dir}/OEBPS/images/blank.{}", format.extension());
+        let imgbuf: image::ImageBuffer<image::Rgb<u8>, Vec<u8>> = image::ImageBuffer::from_pixel(
+            max_width,
+            max_height,
+            image::Rgb(page_options.background.0),
+        );
+        format.save(&imgbuf, Path::new(&blank_page))?;
         sorted_files.insert(
             1,
-            epub::images::Image {
+            Image {
                 path: PathBuf::from(&blank_page),
                 file_name: "blank".to_string(),
                 width: max_width,
                 height: max_height,
+                // Nothing to describe
+                alt: Some(String::new()),
+                ..Image::default()
             },
         );
     }
 
+    fit_pages(
+        &mut sorted_files,
+        max_width,
+        max_height,
+        page_options.native_size,
+    );
+    if let Some(numbering) = page_numbering {
+        number_pages(&mut sorted_files, numbering);
+    }
+
     // Copy image files to the epub directory
-    for file in &sorted_files {
-        padding_image_file(
-            file,
-            max_width,
-            max_height,
-            &format!("{epub_dir}/OEBPS/images/{}.webp", file.file_name),
-        )?;
+    for file in sorted_files.iter().skip(1) {
+        write_page_image(&epub_dir, file, metadata, cache, page_options, format)?;
     }
-    padding_image_file(
-        &sorted_files[0],
+    let cover = Image {
+        file_name: "cover".to_string(),
+        ..sorted_files[0].clone()
+    };
+    write_page_image(&epub_dir, &cover, metadata, cache, page_options, format)?;
+
+    // Create inner files of the epub
+    let chapters = chapters
+        .iter()
+        .map(|x| Chapter {
+            title: x.title.clone(),
+            start: match x.start {
+                0 if !cover_page => 1,
+                0 => 0,
+                start if blank => start + 1,
+                start => start,
+            },
+        })
+        .collect::<Vec<_>>();
+    create_documents(&epub_dir, &sorted_files, metadata, params, &chapters)?;
+
+    // Zip the directory
+    zip_epub(&epub_dir, out)?;
+
+    // Remove the directory
+    rm_directory(&epub_dir)?;
+
+    Ok(())
+}
+
+/// Writes the package document, the navigation documents the EPUB version
+/// calls for, and a part file per page.
+fn create_documents(
+    epub_dir: &str,
+    files: &[Image],
+    metadata: &Metadata,
+    params: &BookParams<'_>,
+    chapters: &[Chapter],
+) -> Result<()> {
+    let BookParams {
+        identifier,
+        language,
         max_width,
         max_height,
-        &format!("{epub_dir}/OEBPS/images/cover.webp"),
-    )?;
+        volumes,
+        page_options,
+        cover_page,
+        page_numbering,
+        version,
+        vendor,
+        ..
+    } = *params;
 
-    // Create inner files of the epub
-    create_nav_file(&epub_dir, max_width, max_height)?;
+    validate_renditions(files, version, vendor)?;
+    let landmarks = landmarks(
+        files,
+        cover_page,
+        page_numbering.map_or(0, |x| x.front_matter),
+    );
+    if version.is_epub3() {
+        create_nav_file(epub_dir, max_width, max_height, chapters, files, &landmarks)?;
+    }
+    if version.is_legacy() {
+        create_ncx_file(epub_dir, identifier, &metadata.title, chapters, files)?;
+    }
     create_opf_file(
-        &epub_dir,
+        epub_dir,
         &OpfParams {
-            identifier: &format!("urn:uuid:{}", Uuid::new_v4()),
-            language: "ja-JP",
+            identifier,
+            language,
             modified: Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string().as_str(),
             max_width,
             max_height,
+            volumes,
+            cover_page,
+            version,
+            vendor,
+            landmarks: &landmarks,
+            page_options,
         },
-        &sorted_files,
-        &metadata,
+        files,
+        metadata,
     )?;
     create_part_files(
-        &epub_dir,
-        &metadata.title,
-        &sorted_files,
-        max_width,
-        max_height,
-    )?;
+        epub_dir,
+        metadata,
+        files,
+        cover_page,
+        page_options,
+        version,
+        vendor,
+    )
+}
 
-    // Zip the directory
-    zip_epub(&epub_dir, &out)?;
+/// Gives every page the size it is shown at: the canvas, or its own size if
+/// `native_size` is set. Generated pages always fill the canvas.
+fn fit_pages(files: &mut [Image], max_width: u32, max_height: u32, native_size: bool) {
+    for file in files.iter_mut() {
+        if !native_size || file.generated.is_some() {
+            // Panels follow the image to where it is placed on the canvas
+            let size = fitted_size(file.width, file.height, max_width, max_height);
+            let offset = ((max_width - size.0) / 2, (max_height - size.1) / 2);
+            for panel in &mut file.panels {
+                *panel = panel.placed(offset, size, (max_width, max_height));
+            }
+            file.width = max_width;
+            file.height = max_height;
+        }
+    }
+    if native_size {
+        // Landscape pages, such as double-page spreads, are not paired with
+        // their neighbours. Generated and reflowing pages take the canvas
+        // and are not centered for it.
+        for file in files.iter_mut().skip(1) {
+            let is_image = file.generated.is_none() && !file.rendition.is_reflowable(None);
+            if is_image && file.spread.is_none() && file.width > file.height {
+                file.spread = Some(PageSpread::Center);
+            }
+        }
+    }
+}
 
-    // Remove the directory
-    rm_directory(&epub_dir)?;
+/// Size a `width` x `height` page is shrunk to so that it fits the canvas,
+/// keeping the aspect ratio as [`padding_image_file`] does.
+#[expect(
+    clippy::cast_possible_truncation,
+    clippy::cast_sign_loss,
+    reason = "the fitted size is no larger than the canvas"
+)]
+fn fitted_size(width: u32, height: u32, max_width: u32, max_height: u32) -> (u32, u32) {
+    if width <= max_width && height <= max_height {
+        return (width, height);
+    }
+    let scale =
+        (f64::from(max_width) / f64::from(width)).min(f64::from(max_height) / f64::from(height));
+    (
+        ((f64::from(width) * scale).round() as u32).clamp(1, max_width),
+        ((f64::from(height) * scale).round() as u32).clamp(1, max_height),
+    )
+}
+
+/// Labels the pages after the cover as `numbering` says.
+fn number_pages(files: &mut [Image], numbering: PageNumbering) {
+    let labels = files
+        .iter()
+        .skip(1)
+        .map(|x| x.label.clone())
+        .collect::<Vec<_>>();
+    for (file, label) in files.iter_mut().skip(1).zip(numbering.labels(&labels)) {
+        file.label = Some(label);
+    }
+}
 
+/// Writes the image of a page to the EPUB directory as `format`, padded to
+/// the size of the page, or drawn if it is a generated cover. Title and text
+/// pages have no image.
+fn write_page_image(
+    epub_dir: &str,
+    file: &Image,
+    metadata: &Metadata,
+    cache: Option<&PageCache>,
+    page_options: &PageOptions,
+    format: PageFormat,
+) -> Result<()> {
+    let out_path = format!("{epub_dir}/OEBPS/{}", file.relative_path(format));
+    match (file.generated, cache) {
+        // Covers read back from a book are kept as they were drawn
+        (Some(GeneratedPage::Cover), _) if file.path.is_file() => {
+            std::fs::copy(&file.path, out_path)?;
+        }
+        (Some(GeneratedPage::Cover), _) => std::fs::write(
+            out_path,
+            cover_svg(metadata, file.width, file.height, page_options.background),
+        )?,
+        (Some(GeneratedPage::TitlePage | GeneratedPage::Text), _) => {}
+        // Pages read back from a book are not encoded again, unless the book
+        // is now written at another size or in another format
+        (None, _) if file.encoded && fits_as_encoded(file, format)? => {
+            std::fs::copy(&file.path, out_path)?;
+        }
+        (None, Some(cache)) => {
+            cache.padding_image_file(
+                file,
+                file.width,
+                file.height,
+                &out_path,
+                page_options,
+                format,
+            )?;
+        }
+        (None, None) => {
+            padding_image_file(
+                file,
+                file.width,
+                file.height,
+                &out_path,
+                page_options,
+                format,
+            )?;
+        }
+    }
     Ok(())
 }
+
+/// Whether the page image read back from a book at the path of `file` is
+/// already in `format` and at the size of the page.
+fn fits_as_encoded(file: &Image, format: PageFormat) -> Result<bool> {
+    let extension = file.path.extension().and_then(|x| x.to_str());
+    Ok(extension == Some(format.extension())
+        && image::image_dimensions(&file.path)? == (file.width, file.height))
+}
+
+pub struct InsertOptions {
+    /// EPUB generated by `img2epub` to insert the pages into
+    pub epub: String,
+    /// Image files or directories of images to insert, in order
+    pub images: Vec<String>,
+    /// Index in the reading order to insert at, where 0 is the cover
+    pub position: usize,
+    /// Output file; the input EPUB is replaced if not set
+    pub out: Option<String>,
+}
+
+/// Inserts new pages into an EPUB generated by `img2epub`.
+///
+/// The book is written anew around the new pages, which are padded (or
+/// shrunk) to its canvas size with the background and padding of the book,
+/// or kept at their own size if the book was written with `native_size`.
+/// The images of the existing pages are copied over as they are.
+///
+/// # Errors
+///
+/// Returns an error if:
+/// - The EPUB was not generated by `img2epub`.
+/// - The position is past the end of the book.
+/// - No image files are found.
+/// - Any file I/O operation fails.
+pub fn insert_pages(opts: InsertOptions) -> Result<()> {
+    let extract_dir = format!("/tmp/epub-{}", Uuid::new_v4());
+    let result = insert_pages_from(opts, &extract_dir);
+    rm_directory(&extract_dir)?;
+    result
+}
+
+fn insert_pages_from(opts: InsertOptions, extract_dir: &str) -> Result<()> {
+    let InsertOptions {
+        epub,
+        images,
+        position,
+        out,
+    } = opts;
+
+    let book = read_generated_book(&epub, extract_dir)?;
+    if position > book.pages.len() {
+        bail!(
+            "position {position} is out of range (the book has {} pages)",
+            book.pages.len()
+        );
+    }
+
+    let mut next = book
+        .pages
+        .iter()
+        .filter_map(|x| x.file_name.parse::<u32>().ok())
+        .max()
+        .map_or(0, |x| x + 1);
+    let mut next_name = || {
+        let name = format!("{next:06}");
+        next += 1;
+        name
+    };
+
+    let mut new_pages = Vec::new();
+    for path in &images {
+        if Path::new(path).is_dir() {
+            new_pages.extend(sort_image_files(path, SortStrategy::default(), None, false)?.0);
+        } else {
+            new_pages.push(open_image_file(Path::new(path), String::new())?);
+        }
+    }
+    if new_pages.is_empty() {
+        bail!("No image files found");
+    }
+    for page in &mut new_pages {
+        page.file_name = next_name();
+    }
+
+    // The old cover needs a name of its own once it is no longer the first page
+    let mut pages = book.pages;
+    if let Some(cover) = pages.first_mut().filter(|x| x.file_name == "cover") {
+        cover.file_name = next_name();
+    }
+    pages.splice(position..position, new_pages);
+
+    build_epub(
+        pages,
+        &book.metadata,
+        &BookParams {
+            identifier: &book.identifier,
+            language: &book.language,
+            max_width: book.width,
+            max_height: book.height,
+            chapters: &Chapter::cover(),
+            volumes: &[],
+            cache: None,
+            page_options: &book.page_options,
+            cover_page: book.cover_page,
+            page_numbering: None,
+            version: book.version,
+            vendor: book.vendor,
+        },
+        out.as_deref().unwrap_or(&epub),
+    )
+}
+
+pub struct MergeOptions {
+    /// Image directories or EPUBs generated by `img2epub`, one per volume
+    pub inputs: Vec<String>,
+    pub out: String,
+    /// Title of the merged book; defaults to the title of the first volume
+    pub title: Option<String>,
+    pub creator: Option<String>,
+    pub publisher: Option<String>,
+    pub publication_date: Option<String>,
+    pub is_rtl: Option<bool>,
+    /// Index of the volume whose cover becomes the cover of the merged book
+    pub cover: usize,
+}
+
+/// Merges several volumes into a single omnibus EPUB.
+///
+/// Each volume becomes a chapter in the table of contents and keeps its own
+/// metadata in the package document. Image directories are read the same way
+/// as [`img2epub`]; a directory without `metadata.json` is titled after its name.
+///
+/// # Errors
+///
+/// Returns an error if:
+/// - No input is given or the cover index is out of range.
+/// - An EPUB input was not generated by `img2epub`.
+/// - An input has no image files.
+/// - Any file I/O operation fails.
+pub fn merge_volumes(opts: MergeOptions) -> Result<()> {
+    let extract_dirs = (0..opts.inputs.len())
+        .map(|_| format!("/tmp/epub-{}", Uuid::new_v4()))
+        .collect::<Vec<_>>();
+    let result = merge_volumes_from(opts, &extract_dirs);
+    for dir in &extract_dirs {
+        rm_directory(dir)?;
+    }
+    result
+}
+
+/// Reads the metadata and pages of a volume given as an image directory.
+fn read_volume_directory(input: &str) -> Result<(Metadata, Vec<Image>)> {
+    let metadata = match read_metadata(input)? {
+        Some(x) => x,
+        None => Metadata {
+            title: Path::new(input)
+                .file_name()
+                .map_or_else(|| input.to_owned(), |x| x.to_string_lossy().into_owned()),
+            creator: None,
+            publisher: None,
+            date: None,
+            is_rtl: false,
+            blank: None,
+            series: None,
+            volume: None,
+            pages: None,
+            cover: None,
+            rendition: Rendition::default(),
+            accessibility: Accessibility::default(),
+        },
+    };
+    let order = page_order(input, &metadata)?;
+    let (pages, _) = sort_image_files(input, SortStrategy::default(), order.as_deref(), false)?;
+    let pages = with_panels(input, pages, false, metadata.is_rtl)?;
+    let pages = with_alt_text(input, pages)?;
+    Ok((metadata, pages))
+}
+
+fn merge_volumes_from(opts: MergeOptions, extract_dirs: &[String]) -> Result<()> {
+    let MergeOptions {
+        inputs,
+        out,
+        title,
+        creator,
+        publisher,
+        publication_date,
+        is_rtl,
+        cover,
+    } = opts;
+
+    if inputs.is_empty() {
+        bail!("No volumes to merge");
+    }
+    if cover >= inputs.len() {
+        bail!("cover volume {cover} is out of range");
+    }
+
+    let mut volumes = Vec::new();
+    for (input, extract_dir) in inputs.iter().zip(extract_dirs) {
+        let (metadata, pages) = if Path::new(input).is_dir() {
+            read_volume_directory(input)?
+        } else {
+            let book = read_generated_book(input, extract_dir)?;
+            (book.metadata, book.pages)
+        };
+        if pages.is_empty() {
+            bail!("No image files found in {input}");
+        }
+        volumes.push((metadata, pages));
+    }
+
+    // The cover of the chosen volume is shown once more in front of the first volume
+    let mut pages = vec![volumes[cover].1[0].clone()];
+    let mut chapters = Vec::new();
+    for (metadata, volume_pages) in &volumes {
+        chapters.push(Chapter {
+            title: metadata.title.clone(),
+            start: pages.len(),
+        });
+        pages.extend(volume_pages.iter().cloned());
+    }
+    for (i, page) in pages.iter_mut().enumerate() {
+        page.file_name = format!("{i:06}");
+    }
+
+    let first = &volumes[0].0;
+    let metadata = Metadata {
+        title: title.unwrap_or_else(|| first.title.clone()),
+        creator: creator.or_else(|| first.creator.clone()),
+        publisher: publisher.or_else(|| first.publisher.clone()),
+        date: publication_date.or_else(|| first.date.clone()),
+        is_rtl: is_rtl.unwrap_or(first.is_rtl),
+        blank: None,
+        series: None,
+        volume: None,
+        pages: None,
+        cover: None,
+        rendition: first.rendition,
+        accessibility: first.accessibility.clone(),
+    };
+    let volumes = volumes.into_iter().map(|(x, _)| x).collect::<Vec<_>>();
+    let max_width = pages.iter().map(|x| x.width).max().unwrap_or(0);
+    let max_height = pages.iter().map(|x| x.height).max().unwrap_or(0);
+
+    build_epub(
+        pages,
+        &metadata,
+        &BookParams {
+            identifier: &format!("urn:uuid:{}", Uuid::new_v4()),
+            language: "ja-JP",
+            max_width,
+            max_height,
+            chapters: &chapters,
+            volumes: &volumes,
+            cache: None,
+            page_options: &PageOptions::default(),
+            cover_page: true,
+            page_numbering: None,
+            version: EpubVersion::default(),
+            vendor: Vendor::default(),
+        },
+        &out,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::{read_dir, write, File},
+        io::Read,
+    };
+
+    use zip::ZipArchive;
+
+    use super::*;
+    use crate::epub::testing::TestDir;
+
+    fn options(image_dir: &TestDir, out: &str) -> EpubOptions {
+        EpubOptions {
+            image_dir: image_dir.0.clone(),
+            out: out.to_string(),
+            title: Some("Test".to_string()),
+            creator: None,
+            publisher: None,
+            publication_date: None,
+            is_rtl: None,
+            blank: None,
+            cache_dir: None,
+            sort: SortStrategy::default(),
+            skip_bad: false,
+            page_options: PageOptions::default(),
+            max_size: None,
+            cover: None,
+            cover_page: true,
+            generate_cover: false,
+            title_page: false,
+            page_numbering: None,
+            version: EpubVersion::default(),
+            vendor: Vendor::default(),
+            orientation: None,
+            spread: None,
+            detect_panels: false,
+        }
+    }
+
+    /// Contents of the file at `name` in the EPUB at `path`.
+    fn entry(path: &str, name: &str) -> Result<Vec<u8>> {
+        let mut archive = ZipArchive::new(File::open(path)?)?;
+        let mut contents = Vec::new();
+        archive.by_name(name)?.read_to_end(&mut contents)?;
+        Ok(contents)
+    }
+
+    #[test]
+    fn pages_are_read_back_and_kept_by_insert() -> Result<()> {
+        let dir = TestDir::new(&["a.png", "b.png", "c.png", "d.png", "new.png"])?;
+        write(
+            dir.path("pages.txt"),
+            "a.png\nb.png label=7 spread=right orientation=landscape\nc.png\nd.png spread=center\n",
+        )?;
+        write(dir.path("alt-text.json"), r#"{"b.png": "Second"}"#)?;
+        write(
+            dir.path("panels.json"),
+            r#"{"c.png": [[0, 0, 40, 30], [0, 30, 40, 30]]}"#,
+        )?;
+        let book = dir.path("book.epub").to_string_lossy().into_owned();
+        img2epub(options(&dir, &book))?;
+
+        let extracted = TestDir::new(&[])?;
+        let read = read_generated_book(&book, &extracted.0)?;
+        assert!(read.cover_page);
+        assert_eq!(read.pages.len(), 4);
+        assert_eq!(read.pages[1].label.as_deref(), Some("7"));
+        assert_eq!(read.pages[1].alt.as_deref(), Some("Second"));
+        assert_eq!(read.pages[1].spread, Some(PageSpread::Right));
+        assert_eq!(
+            read.pages[1].rendition.orientation,
+            Some(Orientation::Landscape)
+        );
+        assert_eq!(read.pages[2].alt, None);
+        assert_eq!(read.pages[2].panels.len(), 2);
+        assert_eq!(read.pages[2].spread, None);
+        assert_eq!(read.pages[3].spread, Some(PageSpread::Center));
+
+        let inserted = dir.path("inserted.epub").to_string_lossy().into_owned();
+        insert_pages(InsertOptions {
+            epub: book.clone(),
+            images: vec![dir.path("new.png").to_string_lossy().into_owned()],
+            position: 2,
+            out: Some(inserted.clone()),
+        })?;
+        for name in [
+            "OEBPS/images/cover.webp",
+            "OEBPS/images/000001.webp",
+            "OEBPS/images/000002.webp",
+            "OEBPS/images/000003.webp",
+        ] {
+            assert_eq!(entry(&book, name)?, entry(&inserted, name)?, "{name}");
+        }
+
+        let extracted = TestDir::new(&[])?;
+        let read = read_generated_book(&inserted, &extracted.0)?;
+        assert_eq!(
+            read.pages
+                .iter()
+                .map(|x| x.file_name.as_str())
+                .collect::<Vec<_>>(),
+            ["cover", "000001", "000004", "000002", "000003"]
+        );
+        assert_eq!(read.pages[1].alt.as_deref(), Some("Second"));
+        assert_eq!(read.pages[3].panels.len(), 2);
+        // Forced spreads and overrides are kept, while the other pages
+        // alternate around them
+        let opf = String::from_utf8(entry(&inserted, "OEBPS/content.opf")?)?;
+        for itemref in [
+            r#"<itemref idref="part1" properties="page-spread-right rendition:orientation-landscape"/>"#,
+            r#"<itemref idref="part2" properties="page-spread-left"/>"#,
+            r#"<itemref idref="part3" properties="page-spread-right"/>"#,
+            r#"<itemref idref="part4" properties="rendition:page-spread-center"/>"#,
+        ] {
+            assert!(opf.contains(itemref), "{itemref}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn metadata_is_rewritten_in_place() -> Result<()> {
+        let dir = TestDir::new(&["a.png", "b.png"])?;
+        let book = dir.path("book.epub").to_string_lossy().into_owned();
+        img2epub(options(&dir, &book))?;
+        let names = |path: &str| -> Result<Vec<String>> {
+            let archive = ZipArchive::new(File::open(path)?)?;
+            Ok(archive.file_names().map(String::from).collect())
+        };
+        let before = names(&book)?;
+
+        set_metadata(
+            &book,
+            &MetadataUpdate {
+                title: Some("Fish & Chips".to_string()),
+                publisher: Some("Press".to_string()),
+                ..MetadataUpdate::default()
+            },
+        )?;
+
+        let opf = String::from_utf8(entry(&book, "OEBPS/content.opf")?)?;
+        assert!(opf.contains("<dc:title>Fish &amp; Chips</dc:title>"));
+        assert!(opf.contains("<dc:publisher>Press</dc:publisher>"));
+        assert_eq!(entry(&book, "mimetype")?, b"application/epub+zip");
+        assert_eq!(names(&book)?, before);
+        let metadata = get_metadata(&book).map_err(|e| anyhow!("{e}"))?;
+        assert_eq!(metadata.title, "Fish & Chips");
+        assert_eq!(metadata.publisher.as_deref(), Some("Press"));
+        // No temporary file is left next to the book
+        assert_eq!(read_dir(&dir.0)?.count(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn insert_keeps_native_size_pages() -> Result<()> {
+        let dir = TestDir::new(&["a.png", "b.png", "new.png"])?;
+        image::RgbImage::from_pixel(120, 40, image::Rgb([0, 0, 200])).save(dir.path("c.png"))?;
+        write(dir.path("pages.txt"), "a.png\nb.png\nc.png\n")?;
+        write(dir.path("panels.json"), r#"{"c.png": [[0, 0, 60, 40]]}"#)?;
+        let book = dir.path("book.epub").to_string_lossy().into_owned();
+        img2epub(EpubOptions {
+            page_options: PageOptions {
+                native_size: true,
+                background: Color::BLACK,
+                padding: Padding::Auto,
+                ..PageOptions::default()
+            },
+            ..options(&dir, &book)
+        })?;
+        let inserted = dir.path("inserted.epub").to_string_lossy().into_owned();
+        insert_pages(InsertOptions {
+            epub: book.clone(),
+            images: vec![dir.path("new.png").to_string_lossy().into_owned()],
+            position: 1,
+            out: Some(inserted.clone()),
+        })?;
+
+        // The pages keep their own size and encoding
+        assert_eq!(
+            entry(&book, "OEBPS/images/000002.webp")?,
+            entry(&inserted, "OEBPS/images/000002.webp")?
+        );
+        let part = String::from_utf8(entry(&inserted, "OEBPS/part3.xhtml")?)?;
+        assert!(part.contains(r#"content="width=120, height=40""#));
+        assert!(part.contains(
+            r#"style="height: 40px; left: 0px; position: absolute; top: 0px; width: 60px""#
+        ));
+        let opf = String::from_utf8(entry(&inserted, "OEBPS/content.opf")?)?;
+        assert!(
+            opf.contains(r#"<itemref idref="part3" properties="rendition:page-spread-center"/>"#)
+        );
+
+        let extracted = TestDir::new(&[])?;
+        let read = read_generated_book(&inserted, &extracted.0)?;
+        assert_eq!(read.page_options.background, Color::BLACK);
+        assert_eq!(read.page_options.padding, Padding::Auto);
+        assert!(read.page_options.native_size);
+        Ok(())
+    }
+
+    #[test]
+    fn larger_cover_is_fitted_to_the_pages() -> Result<()> {
+        let dir = TestDir::new(&["a.png", "b.png"])?;
+        // Outside the image directory, so that it is not a page as well
+        let covers = TestDir::new(&[])?;
+        let cover = covers.path("cover.png");
+        image::RgbImage::from_pixel(80, 120, image::Rgb([200, 0, 0])).save(&cover)?;
+        let book = dir.path("book.epub").to_string_lossy().into_owned();
+        img2epub(EpubOptions {
+            cover: Some(cover.to_string_lossy().into_owned()),
+            ..options(&dir, &book)
+        })?;
+
+        for part in ["part0", "part1", "part2"] {
+            let xhtml = String::from_utf8(entry(&book, &format!("OEBPS/{part}.xhtml"))?)?;
+            assert!(xhtml.contains(r#"content="width=40, height=60""#), "{part}");
+        }
+        let image = image::load_from_memory(&entry(&book, "OEBPS/images/cover.webp")?)?;
+        assert_eq!((image.width(), image.height()), (40, 60));
+        Ok(())
+    }
+
+    #[test]
+    fn epub2_books_name_their_cover() -> Result<()> {
+        let dir = TestDir::new(&["a.png", "b.png"])?;
+        let book = dir.path("book.epub").to_string_lossy().into_owned();
+        img2epub(EpubOptions {
+            version: EpubVersion::Epub2,
+            ..options(&dir, &book)
+        })?;
+
+        let opf = String::from_utf8(entry(&book, "OEBPS/content.opf")?)?;
+        assert!(opf.contains(r#"<meta name="cover" content="cover"/>"#));
+        assert!(
+            opf.contains(r#"<item id="cover" href="images/cover.jpg" media-type="image/jpeg"/>"#)
+        );
+        assert!(opf.contains(r#"<reference type="cover" title="Cover" href="part0.xhtml"/>"#));
+        assert!(opf.contains(r#"<item id="ncx" href="toc.ncx""#));
+        entry(&book, "OEBPS/toc.ncx")?;
+        Ok(())
+    }
+
+    #[test]
+    fn text_page_on_a_landscape_canvas_keeps_native_size() -> Result<()> {
+        let dir = TestDir::new(&["a.png", "b.png"])?;
+        image::RgbImage::from_pixel(120, 40, image::Rgb([0, 0, 200])).save(dir.path("c.png"))?;
+        write(dir.path("d.md"), "# After\n\nThe end.\n")?;
+        // Text pages are only read if listed
+        write(dir.path("pages.txt"), "a.png\nb.png\nc.png\nd.md\n")?;
+        let book = dir.path("book.epub").to_string_lossy().into_owned();
+        img2epub(EpubOptions {
+            page_options: PageOptions {
+                native_size: true,
+                ..PageOptions::default()
+            },
+            ..options(&dir, &book)
+        })?;
+
+        let opf = String::from_utf8(entry(&book, "OEBPS/content.opf")?)?;
+        assert!(
+            opf.contains(r#"<itemref idref="part2" properties="rendition:page-spread-center"/>"#)
+        );
+        assert!(
+            opf.contains(r#"<itemref idref="part3" properties="rendition:layout-reflowable"/>"#)
+        );
+        Ok(())
+    }
+
+    /// Directory of pages of random noise, which compress poorly.
+    fn noise_pages(count: u32) -> Result<TestDir> {
+        let dir = TestDir::new(&[])?;
+        let mut state = 1_u32;
+        for i in 0..count {
+            let page = image::RgbImage::from_fn(200, 300, |_, _| {
+                state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                let [r, g, b, _] = state.to_be_bytes();
+                image::Rgb([r, g, b])
+            });
+            page.save(dir.path(&format!("{i}.png")))?;
+        }
+        Ok(dir)
+    }
+
+    #[test]
+    fn book_is_fitted_to_the_size_budget() -> Result<()> {
+        let dir = noise_pages(3)?;
+        let book = dir.path("book.epub").to_string_lossy().into_owned();
+        let full = img2epub(options(&dir, &book))?.books[0].size;
+
+        let max_size = full / 2;
+        let report = img2epub(EpubOptions {
+            max_size: Some(max_size),
+            ..options(&dir, &book)
+        })?;
+        let built = &report.books[0];
+        assert!(built.size <= max_size);
+        assert_eq!(std::fs::metadata(&book)?.len(), built.size);
+        // The quality is lowered first
+        assert!(built.page_options.quality.is_some());
+        let opf = String::from_utf8(entry(&book, "OEBPS/content.opf")?)?;
+        assert!(opf.contains(r#"media-type="image/jpeg""#));
+        Ok(())
+    }
+
+    #[test]
+    fn book_over_the_size_budget_is_not_left_behind() -> Result<()> {
+        let dir = noise_pages(1)?;
+        let book = dir.path("book.epub").to_string_lossy().into_owned();
+        let result = img2epub(EpubOptions {
+            max_size: Some(100),
+            ..options(&dir, &book)
+        });
+        assert!(result.is_err());
+        assert!(!Path::new(&book).exists());
+        Ok(())
+    }
+
+    #[test]
+    fn panels_outside_the_image_are_an_error() -> Result<()> {
+        let dir = TestDir::new(&["a.png", "b.png"])?;
+        write(dir.path("panels.json"), r#"{"b.png": [[20, 30, 30, 30]]}"#)?;
+        let book = dir.path("book.epub").to_string_lossy().into_owned();
+        assert!(img2epub(options(&dir, &book)).is_err());
+        Ok(())
+    }
+
+    fn merge_options(inputs: &[&str], out: &str) -> MergeOptions {
+        MergeOptions {
+            inputs: inputs.iter().map(|x| (*x).to_string()).collect(),
+            out: out.to_string(),
+            title: None,
+            creator: None,
+            publisher: None,
+            publication_date: None,
+            is_rtl: None,
+            cover: 0,
+        }
+    }
+
+    #[test]
+    fn merged_volumes_keep_their_page_order() -> Result<()> {
+        let first = TestDir::new(&["a.png", "b.png"])?;
+        write(
+            first.path("metadata.json"),
+            r#"{"title": "First", "creator": "Author", "publisher": "Press", "date": "2020-01-01", "is_rtl": true}"#,
+        )?;
+        let second = TestDir::new(&["c.png", "d.png", "e.png"])?;
+        let second_book = second.path("second.epub").to_string_lossy().into_owned();
+        img2epub(EpubOptions {
+            title: Some("Second".to_string()),
+            ..options(&second, &second_book)
+        })?;
+        let out = TestDir::new(&[])?;
+        let book = out.path("merged.epub").to_string_lossy().into_owned();
+        merge_volumes(MergeOptions {
+            cover: 1,
+            ..merge_options(&[&first.0, &second_book], &book)
+        })?;
+
+        let opf = String::from_utf8(entry(&book, "OEBPS/content.opf")?)?;
+        assert!(opf.contains("<dc:title>First</dc:title>"));
+        assert!(opf.contains("<dc:creator>Author</dc:creator>"));
+        assert!(opf.contains("<dc:publisher>Press</dc:publisher>"));
+        assert!(opf.contains("<dc:date>2020-01-01</dc:date>"));
+        assert!(opf.contains(r#"page-progression-direction="rtl""#));
+
+        // The cover of the second volume, then the pages of both in order
+        let image = |name: &str| -> Result<image::RgbImage> {
+            Ok(
+                image::load_from_memory(&entry(&book, &format!("OEBPS/images/{name}.webp"))?)?
+                    .to_rgb8(),
+            )
+        };
+        let source = |dir: &TestDir, name: &str| -> Result<image::RgbImage> {
+            Ok(image::open(dir.path(name))?.to_rgb8())
+        };
+        assert_eq!(
+            image("cover")?.get_pixel(0, 0),
+            source(&second, "c.png")?.get_pixel(0, 0)
+        );
+        for (i, (dir, name)) in [
+            (&first, "a.png"),
+            (&first, "b.png"),
+            (&second, "c.png"),
+            (&second, "d.png"),
+            (&second, "e.png"),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            assert_eq!(
+                image(&format!("{:06}", i + 1))?.get_pixel(0, 0),
+                source(dir, name)?.get_pixel(0, 0),
+                "{name}"
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn merge_options_override_the_first_volume() -> Result<()> {
+        let first = TestDir::new(&["a.png"])?;
+        write(
+            first.path("metadata.json"),
+            r#"{"title": "First", "date": "2020-01-01", "is_rtl": false}"#,
+        )?;
+        let out = TestDir::new(&[])?;
+        let book = out.path("merged.epub").to_string_lossy().into_owned();
+        merge_volumes(MergeOptions {
+            title: Some("Omnibus".to_string()),
+            publication_date: Some("2024-05-01".to_string()),
+            ..merge_options(&[&first.0], &book)
+        })?;
+
+        let opf = String::from_utf8(entry(&book, "OEBPS/content.opf")?)?;
+        assert!(opf.contains("<dc:title>Omnibus</dc:title>"));
+        assert!(opf.contains("<dc:date>2024-05-01</dc:date>"));
+        assert!(merge_volumes(MergeOptions {
+            cover: 1,
+            ..merge_options(&[&first.0], &book)
+        })
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn merged_directories_keep_panels_and_alt_text() -> Result<()> {
+        let dir = TestDir::new(&["a.png", "b.png"])?;
+        write(dir.path("alt-text.json"), r#"{"b.png": "Second"}"#)?;
+        write(dir.path("panels.json"), r#"{"b.png": [[0, 0, 40, 30]]}"#)?;
+        let out = TestDir::new(&[])?;
+        let book = out.path("merged.epub").to_string_lossy().into_owned();
+        merge_volumes(merge_options(&[&dir.0], &book))?;
+
+        let extracted = TestDir::new(&[])?;
+        let read = read_generated_book(&book, &extracted.0)?;
+        let page = read
+            .pages
+            .iter()
+            .find(|x| x.alt.is_some())
+            .ok_or_else(|| anyhow!("no described page"))?;
+        assert_eq!(page.alt.as_deref(), Some("Second"));
+        assert_eq!(page.panels.len(), 1);
+        Ok(())
+    }
+
+    /// Pages of the files at `names` in `dir`.
+    fn pages(dir: &TestDir, names: &[&str]) -> Vec<Image> {
+        names
+            .iter()
+            .map(|name| Image {
+                path: dir.path(name),
+                file_name: (*name).to_string(),
+                ..Image::default()
+            })
+            .collect()
+    }
+
+    /// File names of the pages in each volume.
+    fn volumes(parts: &[Vec<Image>]) -> Vec<Vec<&str>> {
+        parts
+            .iter()
+            .map(|part| part.iter().map(|x| x.file_name.as_str()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn pages_are_split_by_count() -> Result<()> {
+        let names = ["1.png", "2.png", "3.png", "4.png", "5.png"];
+        let dir = TestDir::new(&names)?;
+        let parts = split_pages(&pages(&dir, &names), &SplitMode::Pages(2))?;
+        assert_eq!(
+            volumes(&parts),
+            [
+                vec!["1.png", "2.png"],
+                vec!["3.png", "4.png"],
+                vec!["5.png"]
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn pages_are_split_by_source_size() -> Result<()> {
+        let dir = TestDir::new(&[])?;
+        let names = ["1", "2", "3", "4"];
+        for (name, size) in names.iter().zip([40, 50, 20, 120]) {
+            write(dir.path(name), vec![0; size])?;
+        }
+        let parts = split_pages(&pages(&dir, &names), &SplitMode::SourceSize(100))?;
+        // A page larger than the limit gets a volume of its own
+        assert_eq!(volumes(&parts), [vec!["1", "2"], vec!["3"], vec!["4"]]);
+        Ok(())
+    }
+
+    #[test]
+    fn pages_are_split_by_chapter() -> Result<()> {
+        let names = ["a/1.png", "a/2.png", "b/1.png", "c/1.png", "c/2.png"];
+        let dir = TestDir::new(&names)?;
+        let parts = split_pages(&pages(&dir, &names), &SplitMode::Chapters)?;
+        assert_eq!(
+            volumes(&parts),
+            [
+                vec!["a/1.png", "a/2.png"],
+                vec!["b/1.png"],
+                vec!["c/1.png", "c/2.png"]
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn volume_limit_of_zero_is_an_error() -> Result<()> {
+        let names = ["1.png"];
+        let dir = TestDir::new(&names)?;
+        let files = pages(&dir, &names);
+        assert!(split_pages(&files, &SplitMode::Pages(0)).is_err());
+        assert!(split_pages(&files, &SplitMode::SourceSize(0)).is_err());
+        Ok(())
+    }
+}